@@ -192,6 +192,26 @@ async fn test_first_line_pattern(cx: &mut TestAppContext) {
         .name(),
         "JavaScript".into()
     );
+
+    // Files that aren't open in a buffer are matched by their content too.
+    assert_eq!(
+        languages
+            .language_for_file_path_and_content(
+                Path::new("the/script"),
+                Some("#!/bin/env node\nconsole.log(1);\n"),
+                None,
+            )
+            .await
+            .unwrap()
+            .name(),
+        "JavaScript".into()
+    );
+    assert!(
+        languages
+            .language_for_file_path_and_content(Path::new("the/script"), Some("nothing"), None)
+            .await
+            .is_err()
+    );
 }
 
 #[gpui::test]
@@ -697,7 +697,21 @@ impl LanguageRegistry {
         self: &Arc<Self>,
         path: &'a Path,
     ) -> impl Future<Output = Result<Arc<Language>>> + 'a {
-        let available_language = self.language_for_file_internal(path, None, None);
+        self.language_for_file_path_and_content(path, None, None)
+    }
+
+    /// Like [`Self::language_for_file_path`], but also takes into account the file's content and
+    /// the user's file type associations, for files that aren't open in a buffer.
+    pub fn language_for_file_path_and_content<'a>(
+        self: &Arc<Self>,
+        path: &'a Path,
+        content: Option<&str>,
+        user_file_types: Option<&FxHashMap<Arc<str>, GlobSet>>,
+    ) -> impl Future<Output = Result<Arc<Language>>> + 'a {
+        // Only the first line is matched against each language's `first_line_pattern`.
+        let first_line = content.map(|content| Rope::from(content.lines().next().unwrap_or("")));
+        let available_language =
+            self.language_for_file_internal(path, first_line.as_ref(), user_file_types);
 
         let this = self.clone();
         async move {
@@ -1122,6 +1122,11 @@ impl AllLanguageSettings {
     pub fn edit_predictions_mode(&self) -> EditPredictionsMode {
         self.edit_predictions.mode
     }
+
+    /// Returns the file type associations configured by the user, keyed by language name.
+    pub fn file_types(&self) -> &FxHashMap<Arc<str>, GlobSet> {
+        &self.file_types
+    }
 }
 
 fn merge_with_editorconfig(settings: &mut LanguageSettings, cfg: &EditorconfigProperties) {
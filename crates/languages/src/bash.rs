@@ -25,6 +25,40 @@ mod tests {
     use unindent::Unindent;
     use util::test::marked_text_offsets;
 
+    #[gpui::test]
+    async fn test_bash_outline(cx: &mut TestAppContext) {
+        let language = crate::language("bash", tree_sitter_bash::LANGUAGE.into());
+
+        let text = r#"
+            #!/usr/bin/env bash
+            set -euo pipefail
+
+            function build() {
+                cargo build --release
+            }
+
+            deploy() {
+                cleanup() {
+                    rm -rf dist
+                }
+                build
+                cleanup
+            }
+        "#
+        .unindent();
+
+        let buffer = cx.new(|cx| Buffer::local(text, cx).with_language(language, cx));
+        let outline = buffer.read_with(cx, |buffer, _| buffer.snapshot().outline(None).unwrap());
+        assert_eq!(
+            outline
+                .items
+                .iter()
+                .map(|item| (item.text.as_str(), item.depth))
+                .collect::<Vec<_>>(),
+            &[("function build", 0), ("deploy", 0), ("cleanup", 1)]
+        );
+    }
+
     #[gpui::test]
     async fn test_bash_autoindent(cx: &mut TestAppContext) {
         cx.executor().set_block_on_ticks(usize::MAX..=usize::MAX);
@@ -0,0 +1,3 @@
+(function_definition
+    "function"? @context
+    name: (word) @name) @item
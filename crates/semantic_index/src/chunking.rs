@@ -2,6 +2,7 @@ use language::{Language, with_parser, with_query_cursor};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
     cmp::{self, Reverse},
     ops::Range,
     path::Path,
@@ -9,7 +10,7 @@ use std::{
 };
 use streaming_iterator::StreamingIterator;
 use tree_sitter::QueryCapture;
-use util::ResultExt as _;
+use util::{ResultExt as _, paths::PathMatcher};
 
 #[derive(Copy, Clone)]
 struct ChunkSizeRange {
@@ -22,14 +23,185 @@ const CHUNK_SIZE_RANGE: ChunkSizeRange = ChunkSizeRange {
     max: 8192,
 };
 
+/// The smallest allowed `fallback_chunk_size`, so that every chunk can fit a few characters.
+const MIN_FALLBACK_CHUNK_SIZE: usize = 64;
+
+/// The average line length above which a large file is assumed to be minified or generated.
+const MAX_AVERAGE_LINE_LEN: usize = 500;
+
+/// Machine-written files that are common in repositories but not worth searching.
+const LOCKFILE_NAMES: &[&str] = &[
+    "Cargo.lock",
+    "Gemfile.lock",
+    "bun.lock",
+    "composer.lock",
+    "flake.lock",
+    "go.sum",
+    "package-lock.json",
+    "pnpm-lock.yaml",
+    "poetry.lock",
+    "yarn.lock",
+];
+
+/// Directories of dependencies and build output, which are often not gitignored but rarely what
+/// users want to search. Package managers create these wherever a package is installed.
+const VENDORED_DIR_NAMES: &[&str] = &[".venv", "bower_components", "node_modules"];
+
+/// Like [`VENDORED_DIR_NAMES`], but these names are also common for first-party directories, such
+/// as `src/vendor`. Each is only vendored at the root of the worktree, or next to one of the
+/// manifests of the tools that create it.
+const AMBIGUOUS_VENDORED_DIRS: &[(&str, &[&str])] = &[
+    ("Pods", &["Podfile"]),
+    ("dist", &["package.json"]),
+    ("target", &["Cargo.toml"]),
+    ("vendor", &["Gemfile", "composer.json", "go.mod"]),
+];
+
+/// The fraction of a file's bytes that may be covered by syntax errors before we stop trusting
+/// its syntax tree, and split it by lines instead.
+const MAX_PARSE_ERROR_DENSITY: f32 = 0.1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     pub range: Range<usize>,
     pub digest: [u8; 32],
 }
 
-pub fn chunk_text(text: &str, language: Option<&Arc<Language>>, path: &Path) -> Vec<Chunk> {
-    chunk_text_with_size_range(text, language, path, CHUNK_SIZE_RANGE)
+/// Options controlling how files are split into chunks, and how those chunks are embedded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChunkingOptions {
+    /// The number of bytes at the end of each chunk that are repeated at the start of the next
+    /// one, rounded to whole lines and at most half the maximum chunk size. Only applies to files
+    /// that are split by lines because they have no syntax tree. The overlap counts towards the
+    /// maximum chunk size.
+    pub fallback_overlap: usize,
+    /// The maximum size in bytes of chunks of files that are split by lines because they have no
+    /// syntax tree, which defaults to the size used for all other files.
+    pub fallback_chunk_size: Option<usize>,
+    /// Whether to embed each chunk after a header naming its file, so that identical code in
+    /// different files gets different embeddings. Search results still cover only the chunk.
+    pub path_context: bool,
+    /// Whether to collapse each run of whitespace in a chunk into a single space before embedding
+    /// it, so that indentation and blank lines don't dilute its embedding. Search results still
+    /// show the chunk as it appears in the file.
+    pub normalize_whitespace: bool,
+    /// Whether to index files in directories such as `node_modules`, and `target` next to a
+    /// `Cargo.toml` or at the root, which are skipped by default.
+    pub index_vendored_dirs: bool,
+    /// Worktree-relative paths of files that are never indexed, in addition to those ignored by
+    /// git. Embeddings stored for a file are deleted once it matches.
+    pub exclude_globs: PathMatcher,
+}
+
+impl ChunkingOptions {
+    /// Describes the options that change the text embedded for each chunk. Embeddings computed
+    /// with different options can't be reused for one another, even for identical chunks.
+    pub fn embedded_text_options(&self) -> String {
+        let mut options = Vec::new();
+        if self.path_context {
+            options.push("path_context");
+        }
+        if self.normalize_whitespace {
+            options.push("normalize_whitespace");
+        }
+        options.join(",")
+    }
+
+    /// Returns the text embedded for a chunk of the file at the given worktree-relative path, which
+    /// is borrowed if it's the chunk itself. The file's header is only added if `path` is given.
+    pub fn embedded_text<'a>(&self, text: &'a str, path: Option<&Path>) -> Cow<'a, str> {
+        let text = if self.normalize_whitespace {
+            Cow::Owned(normalize_whitespace(text))
+        } else {
+            Cow::Borrowed(text)
+        };
+        match path {
+            Some(path) if self.path_context => Cow::Owned(path_header(path) + &text),
+            _ => text,
+        }
+    }
+
+    /// Returns whether the file at the given worktree-relative path should not be indexed.
+    /// `is_file` returns whether a file exists at another worktree-relative path.
+    pub fn is_excluded(&self, path: &Path, is_file: impl Fn(&Path) -> bool) -> bool {
+        (!self.index_vendored_dirs && is_vendored_path(path, is_file))
+            || self.exclude_globs.is_match(path)
+    }
+}
+
+/// Collapses each run of whitespace in `text` into a single space, and trims both ends.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The header that chunks are embedded after when [`ChunkingOptions::path_context`] is set.
+pub fn path_header(path: &Path) -> String {
+    format!("File: {}\n\n", path.display())
+}
+
+/// Returns whether a file looks like a lockfile or minified code, whose embeddings would only add
+/// noise to search results.
+pub fn is_generated_file(path: &Path, text: &str) -> bool {
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if LOCKFILE_NAMES.contains(&file_name) || file_name.contains(".min.") {
+        return true;
+    }
+
+    let line_count = text.lines().count().max(1);
+    text.len() > CHUNK_SIZE_RANGE.max && text.len() / line_count > MAX_AVERAGE_LINE_LEN
+}
+
+/// Returns whether a worktree-relative path is inside a dependency or build output directory.
+/// `is_file` returns whether a file exists at another worktree-relative path.
+pub fn is_vendored_path(path: &Path, is_file: impl Fn(&Path) -> bool) -> bool {
+    let Some(parent) = path.parent() else {
+        return false;
+    };
+    parent.ancestors().any(|dir| {
+        let (Some(name), Some(containing_dir)) =
+            (dir.file_name().and_then(|name| name.to_str()), dir.parent())
+        else {
+            return false;
+        };
+        VENDORED_DIR_NAMES.contains(&name)
+            || AMBIGUOUS_VENDORED_DIRS
+                .iter()
+                .find(|(dir_name, _)| *dir_name == name)
+                .is_some_and(|(_, manifests)| {
+                    containing_dir.as_os_str().is_empty()
+                        || manifests
+                            .iter()
+                            .any(|manifest| is_file(&containing_dir.join(manifest)))
+                })
+    })
+}
+
+pub fn chunk_text(
+    text: &str,
+    language: Option<&Arc<Language>>,
+    path: &Path,
+    options: &ChunkingOptions,
+) -> Vec<Chunk> {
+    chunk_text_with_size_range(text, language, path, CHUNK_SIZE_RANGE, options)
+}
+
+/// Like [`chunk_text`], but keeps every chunk within `max_chunk_size` bytes, including any
+/// overlap, such as when the embedding model accepts less text than a chunk of the default size.
+pub fn chunk_text_with_max_size(
+    text: &str,
+    language: Option<&Arc<Language>>,
+    path: &Path,
+    max_chunk_size: usize,
+    options: &ChunkingOptions,
+) -> Vec<Chunk> {
+    let max = max_chunk_size.clamp(MIN_FALLBACK_CHUNK_SIZE, CHUNK_SIZE_RANGE.max);
+    let size_config = ChunkSizeRange {
+        min: CHUNK_SIZE_RANGE.min.min(max / 2),
+        max,
+    };
+    chunk_text_with_size_range(text, language, path, size_config, options)
 }
 
 fn chunk_text_with_size_range(
@@ -37,9 +209,58 @@ fn chunk_text_with_size_range(
     language: Option<&Arc<Language>>,
     path: &Path,
     size_config: ChunkSizeRange,
+    options: &ChunkingOptions,
 ) -> Vec<Chunk> {
-    let ranges = syntactic_ranges(text, language, path).unwrap_or_default();
-    chunk_text_with_syntactic_ranges(text, &ranges, size_config)
+    if let Some(ranges) = syntactic_ranges(text, language, path) {
+        chunk_text_with_syntactic_ranges(text, &ranges, size_config)
+    } else {
+        // Leave room for the overlap that's prepended to each chunk, so that chunks stay within
+        // the maximum size.
+        let overlap = options.fallback_overlap.min(size_config.max / 2);
+        let mut max = size_config.max - overlap;
+        if let Some(fallback_chunk_size) = options.fallback_chunk_size {
+            max = max.min(fallback_chunk_size.max(MIN_FALLBACK_CHUNK_SIZE));
+        }
+        let size_config = ChunkSizeRange {
+            min: size_config.min.min(max),
+            max,
+        };
+        let chunks = chunk_text_with_syntactic_ranges(text, &[], size_config);
+        overlap_chunks(text, chunks, overlap)
+    }
+}
+
+fn overlap_chunks(text: &str, chunks: Vec<Chunk>, overlap: usize) -> Vec<Chunk> {
+    if overlap == 0 {
+        return chunks;
+    }
+
+    let bytes = text.as_bytes();
+    let mut overlapped_chunks = Vec::with_capacity(chunks.len());
+    let mut prev_start = None;
+    for chunk in chunks {
+        let mut start = chunk.range.start;
+        if let Some(prev_start) = prev_start {
+            // Begin the overlap at a line boundary, so that no chunk starts in the middle of a line.
+            let overlap_start = start.saturating_sub(overlap).max(prev_start);
+            start = if overlap_start == 0 || bytes[overlap_start - 1] == b'\n' {
+                overlap_start
+            } else {
+                bytes[overlap_start..chunk.range.start]
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                    .map_or(chunk.range.start, |ix| overlap_start + ix + 1)
+            };
+        }
+        prev_start = Some(chunk.range.start);
+
+        let range = start..chunk.range.end;
+        overlapped_chunks.push(Chunk {
+            digest: Sha256::digest(&text[range.clone()]).into(),
+            range,
+        });
+    }
+    overlapped_chunks
 }
 
 fn syntactic_ranges(
@@ -60,6 +281,14 @@ fn syntactic_ranges(
         return None;
     };
 
+    // Files that are being edited are often temporarily invalid. Rather than chunking around
+    // whatever outline items survived, treat badly broken files as plain text.
+    let max_error_len = (text.len() as f32 * MAX_PARSE_ERROR_DENSITY) as usize;
+    if has_too_many_parse_errors(tree.root_node(), max_error_len) {
+        log::warn!("too many syntax errors in {path:?}, chunking it by lines");
+        return None;
+    }
+
     struct RowInfo {
         offset: usize,
         is_comment: bool,
@@ -115,10 +344,61 @@ fn syntactic_ranges(
             .collect::<Vec<_>>()
     });
 
+    // Markdown outline items are just headings, so chunk around the sections they begin instead.
+    if language.name() == "Markdown".into() {
+        ranges.extend(markdown_section_ranges(tree.root_node()));
+    }
+
     ranges.sort_unstable_by_key(|range| (range.start, Reverse(range.end)));
     Some(ranges)
 }
 
+/// Returns the ranges of the multi-line Markdown sections, each spanning a heading and the content
+/// up to the next heading of the same or a higher level.
+fn markdown_section_ranges(root: tree_sitter::Node) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        let is_section = node.kind() == "section";
+        if is_section && node.end_position().row > node.start_position().row {
+            ranges.push(node.byte_range());
+        }
+
+        // Sections are only nested within other sections, so there's no need to descend elsewhere.
+        if (is_section || cursor.depth() == 0) && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return ranges;
+            }
+        }
+    }
+}
+
+/// Returns whether error nodes within the given node cover more than `max_error_len` bytes.
+fn has_too_many_parse_errors(root: tree_sitter::Node, max_error_len: usize) -> bool {
+    let mut error_len = 0;
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        if node.is_error() {
+            error_len += node.byte_range().len();
+            if error_len > max_error_len {
+                return true;
+            }
+        } else if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return false;
+            }
+        }
+    }
+}
+
 fn chunk_text_with_syntactic_ranges(
     text: &str,
     mut syntactic_ranges: &[Range<usize>],
@@ -247,6 +527,7 @@ mod tests {
                 min: text.find('}').unwrap(),
                 max: text.find("Self {").unwrap(),
             },
+            &ChunkingOptions::default(),
         );
 
         // The entire impl cannot fit in a chunk, so it is split.
@@ -281,6 +562,7 @@ mod tests {
                 min: text.find('{').unwrap(),
                 max: text.find('V').unwrap(),
             },
+            &ChunkingOptions::default(),
         );
 
         // Two single-line structs can fit in a chunk.
@@ -298,6 +580,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_chunk_markdown_by_sections() {
+        let language = markdown_language();
+
+        let text = "
+            # Installation
+
+            Download the installer.
+
+            ## Linux
+
+            Use the tarball.
+
+            # Usage
+
+            Open a project.
+            Search it.
+        "
+        .unindent();
+
+        let chunks = chunk_text_with_size_range(
+            &text,
+            Some(&language),
+            Path::new("README.md"),
+            ChunkSizeRange {
+                min: text.find("Download").unwrap(),
+                max: text.find("Search").unwrap(),
+            },
+            &ChunkingOptions::default(),
+        );
+
+        // Headings are single-line outline items, but chunks still end where a section ends.
+        assert_chunks(&text, &chunks, &["# Installation", "# Usage"]);
+    }
+
     #[test]
     fn test_chunk_with_long_lines() {
         let language = rust_language();
@@ -315,6 +632,7 @@ mod tests {
             Some(&language),
             Path::new("lib.rs"),
             ChunkSizeRange { min: 32, max: 64 },
+            &ChunkingOptions::default(),
         );
 
         // The line is too long to fit in one chunk
@@ -378,14 +696,256 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_chunk_text_with_fallback_overlap() {
+        let text = (0..8)
+            .map(|ix| format!("line {ix:02}\n"))
+            .collect::<String>();
+        let size_config = ChunkSizeRange { min: 16, max: 32 };
+
+        let chunks = chunk_text_with_size_range(
+            &text,
+            None,
+            Path::new("notes.txt"),
+            size_config,
+            &ChunkingOptions::default(),
+        );
+        assert_chunks(&text, &chunks, &["line 00", "line 04"]);
+
+        let chunks = chunk_text_with_size_range(
+            &text,
+            None,
+            Path::new("notes.txt"),
+            size_config,
+            &ChunkingOptions {
+                fallback_overlap: 10,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| &text[chunk.range.clone()])
+                .collect::<Vec<_>>(),
+            [
+                "line 00\nline 01\n",
+                "line 01\nline 02\nline 03\n",
+                "line 03\nline 04\nline 05\n",
+                "line 05\nline 06\nline 07\n",
+            ]
+        );
+        // The overlap counts towards the maximum chunk size.
+        assert!(
+            chunks
+                .iter()
+                .all(|chunk| chunk.range.len() <= size_config.max)
+        );
+        let digest: [u8; 32] = Sha256::digest(&text[chunks[1].range.clone()]).into();
+        assert_eq!(chunks[1].digest, digest);
+
+        // Files that are split syntactically are unaffected by the overlap.
+        let language = rust_language();
+        let text = "
+            struct T {}
+            struct U {}
+            struct V {}
+            struct W {
+                a: T,
+                b: U,
+            }
+        "
+        .unindent();
+        let size_config = ChunkSizeRange {
+            min: text.find('{').unwrap(),
+            max: text.find('V').unwrap(),
+        };
+        let chunks = chunk_text_with_size_range(
+            &text,
+            Some(&language),
+            Path::new("lib.rs"),
+            size_config,
+            &ChunkingOptions {
+                fallback_overlap: 10,
+                ..Default::default()
+            },
+        );
+        assert_chunks(&text, &chunks, &["struct T", "struct V", "struct W", "}"]);
+    }
+
+    #[test]
+    fn test_chunk_text_with_parse_errors() {
+        let language = rust_language();
+
+        // A small error in an otherwise valid file keeps the syntactic ranges.
+        let text = "
+            struct Person {
+                name: String,
+            }
+
+            impl Person {
+                fn name(&self) -> &str {
+                    let unused = ;
+                    &self.name
+                }
+
+                fn set_name(&mut self, name: String) {
+                    self.name = name;
+                }
+
+                fn greeting(&self) -> String {
+                    self.name.to_uppercase()
+                }
+            }
+        "
+        .unindent();
+        let ranges = syntactic_ranges(&text, Some(&language), Path::new("lib.rs")).unwrap();
+        assert!(!ranges.is_empty());
+
+        let text = "
+            fn main( {
+                let = = ;
+            )) impl for struct fn {
+        "
+        .unindent()
+        .repeat(10);
+        assert_eq!(
+            syntactic_ranges(&text, Some(&language), Path::new("lib.rs")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_vendored_path() {
+        let no_files = |_: &Path| false;
+        assert!(is_vendored_path(
+            Path::new("node_modules/pad/index.js"),
+            no_files
+        ));
+        assert!(is_vendored_path(
+            Path::new("web/node_modules/pad/index.js"),
+            no_files
+        ));
+        assert!(is_vendored_path(
+            Path::new("target/debug/build.rs"),
+            no_files
+        ));
+        assert!(is_vendored_path(Path::new("vendor/lib/mod.rs"), no_files));
+        assert!(!is_vendored_path(Path::new("src/vendor.rs"), no_files));
+        assert!(!is_vendored_path(
+            Path::new("src/distance/mod.rs"),
+            no_files
+        ));
+        // First-party directories that share a name with vendored ones are only vendored at the
+        // root of the worktree, or next to the manifest of a tool that creates them.
+        assert!(!is_vendored_path(Path::new("src/vendor/mod.rs"), no_files));
+        assert!(!is_vendored_path(
+            Path::new("crates/foo/target/build.rs"),
+            no_files
+        ));
+        let manifests = |path: &Path| {
+            [
+                Path::new("crates/foo/Cargo.toml"),
+                Path::new("ios/Podfile"),
+                Path::new("packages/web/package.json"),
+            ]
+            .contains(&path)
+        };
+        assert!(is_vendored_path(
+            Path::new("crates/foo/target/debug/build.rs"),
+            manifests
+        ));
+        assert!(is_vendored_path(
+            Path::new("ios/Pods/Alamofire/Source.swift"),
+            manifests
+        ));
+        assert!(is_vendored_path(
+            Path::new("packages/web/dist/index.js"),
+            manifests
+        ));
+        assert!(!is_vendored_path(
+            Path::new("packages/web/target/index.js"),
+            manifests
+        ));
+        assert!(!is_vendored_path(
+            Path::new("crates/foo/src/target/mod.rs"),
+            manifests
+        ));
+    }
+
+    #[test]
+    fn test_is_generated_file() {
+        let source = "fn main() {\n    println!(\"hello\");\n}\n".repeat(500);
+        assert!(!is_generated_file(Path::new("src/main.rs"), &source));
+        assert!(is_generated_file(Path::new("Cargo.lock"), "version = 4\n"));
+        assert!(is_generated_file(
+            Path::new("web/package-lock.json"),
+            "{}\n"
+        ));
+        assert!(is_generated_file(Path::new("dist/app.min.js"), "var a=1;"));
+
+        let minified = "var a=1;".repeat(2000);
+        assert!(is_generated_file(Path::new("dist/app.js"), &minified));
+        // Short files are never considered minified, whatever their line length.
+        assert!(!is_generated_file(Path::new("app.js"), &"x".repeat(1000)));
+    }
+
     #[test]
     fn test_chunk_text() {
         let text = "a\n".repeat(1000);
-        let chunks = chunk_text(&text, None, Path::new("lib.rs"));
+        let chunks = chunk_text(
+            &text,
+            None,
+            Path::new("lib.rs"),
+            &ChunkingOptions::default(),
+        );
         assert_eq!(
             chunks.len(),
             ((2000_f64) / (CHUNK_SIZE_RANGE.max as f64)).ceil() as usize
         );
+
+        let chunks = chunk_text(
+            &text,
+            None,
+            Path::new("lib.rs"),
+            &ChunkingOptions {
+                fallback_chunk_size: Some(500),
+                ..Default::default()
+            },
+        );
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.range.len() <= 500));
+    }
+
+    #[test]
+    fn test_chunk_text_with_max_size() {
+        let language = rust_language();
+        let text = "fn a() {\n    let x = 1;\n}\n".repeat(100);
+        let chunks = chunk_text_with_max_size(
+            &text,
+            Some(&language),
+            Path::new("lib.rs"),
+            512,
+            &ChunkingOptions::default(),
+        );
+        check_chunk_invariants(&text, &chunks);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.range.len() <= 512));
+
+        // Overlapping chunks of files without a syntax tree stay within the limit too.
+        let text = "a\n".repeat(1000);
+        let chunks = chunk_text_with_max_size(
+            &text,
+            None,
+            Path::new("notes.txt"),
+            512,
+            &ChunkingOptions {
+                fallback_overlap: 100,
+                fallback_chunk_size: Some(1000),
+                ..Default::default()
+            },
+        );
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.range.len() <= 512));
     }
 
     fn rust_language() -> Arc<Language> {
@@ -412,4 +972,22 @@ mod tests {
             .unwrap(),
         )
     }
+
+    fn markdown_language() -> Arc<Language> {
+        Arc::new(
+            Language::new(
+                LanguageConfig {
+                    name: "Markdown".into(),
+                    matcher: LanguageMatcher {
+                        path_suffixes: vec!["md".to_string()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                Some(tree_sitter_md::LANGUAGE.into()),
+            )
+            .with_outline_query("(atx_heading . (_) @context . (_) @name) @item")
+            .unwrap(),
+        )
+    }
 }
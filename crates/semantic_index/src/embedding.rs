@@ -1,21 +1,56 @@
+mod cache;
 mod lmstudio;
 mod ollama;
 mod open_ai;
+mod truncated;
 
+pub use cache::*;
 pub use lmstudio::*;
 pub use ollama::*;
 pub use open_ai::*;
-use sha2::{Digest, Sha256};
+pub use truncated::*;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use futures::{FutureExt, future::BoxFuture};
+use gpui::BackgroundExecutor;
 use serde::{Deserialize, Serialize};
-use std::{fmt, future};
+use sha2::{Digest, Sha256};
+use std::{fmt, future, time::Duration};
+
+/// The number of bytes of text assumed to make up each token when estimating token counts. Code
+/// and non-English text often take fewer bytes per token than English prose, so this errs low to
+/// keep batches and chunks within the providers' limits.
+pub(crate) const ESTIMATED_BYTES_PER_TOKEN: usize = 3;
+
+/// How long to wait for a batch of embeddings by default before treating the request as failed.
+pub const DEFAULT_EMBED_TIMEOUT: Duration = Duration::from_secs(120);
 
 /// Trait for embedding providers. Texts in, vectors out.
 pub trait EmbeddingProvider: Sync + Send {
     fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>>;
     fn batch_size(&self) -> usize;
+    /// The number of dimensions of the embeddings produced by this provider.
+    fn dimension(&self) -> usize;
+    /// Identifies the model used to compute embeddings, so that embeddings from different models
+    /// are never mixed. Include anything else that changes the stored embeddings, such as the
+    /// prompt that documents are wrapped in.
+    fn model_id(&self) -> &str;
+    /// The maximum number of tokens, as estimated by [`TextToEmbed::estimated_token_count`], that
+    /// the provider accepts in a single batch.
+    fn max_batch_tokens(&self) -> Option<usize> {
+        None
+    }
+    /// The maximum number of tokens that the model reads from a single text. Depending on the
+    /// provider, longer texts are truncated or rejected.
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+    /// The smaller dimensions that this model's embeddings can be truncated to and still be
+    /// useful, because the model was trained with Matryoshka representation learning. Empty if
+    /// truncation isn't supported.
+    fn truncation_dimensions(&self) -> &[usize] {
+        &[]
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
@@ -31,17 +66,30 @@ impl Embedding {
         }
 
         norm = norm.sqrt();
-        for dimension in &mut embedding {
-            *dimension /= norm;
+        // Dividing a zero vector by its norm would fill it with NaNs.
+        if norm > 0. {
+            for dimension in &mut embedding {
+                *dimension /= norm;
+            }
         }
 
         Self(embedding)
     }
 
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Returns whether every value of this embedding is finite, which providers don't guarantee.
+    pub fn is_finite(&self) -> bool {
+        self.0.iter().all(|value| value.is_finite())
+    }
+
+    /// Keeps the first `dimension` values of this embedding and normalizes the result.
+    pub fn truncate(&self, dimension: usize) -> Self {
+        Self::new(self.0[..dimension.min(self.len())].to_vec())
+    }
+
     pub fn similarity(&self, others: &[Embedding]) -> (f32, usize) {
         debug_assert!(others.iter().all(|other| self.0.len() == other.0.len()));
         others
@@ -87,6 +135,9 @@ impl fmt::Display for Embedding {
 pub struct TextToEmbed<'a> {
     pub text: &'a str,
     pub digest: [u8; 32],
+    /// Whether this is a search query rather than a document, which some models expect to be
+    /// marked differently.
+    pub is_query: bool,
 }
 
 impl<'a> TextToEmbed<'a> {
@@ -95,8 +146,68 @@ impl<'a> TextToEmbed<'a> {
         Self {
             text,
             digest: digest.into(),
+            is_query: false,
         }
     }
+
+    pub fn new_query(text: &'a str) -> Self {
+        Self {
+            is_query: true,
+            ..Self::new(text)
+        }
+    }
+
+    /// A rough estimate of the length of this text in tokens.
+    pub fn estimated_token_count(&self) -> usize {
+        self.text.len().div_ceil(ESTIMATED_BYTES_PER_TOKEN)
+    }
+}
+
+/// Embeds `texts` with `provider`, failing if it doesn't respond within `timeout`, so that a
+/// provider that never responds can't stall indexing or search forever.
+pub(crate) async fn embed_with_timeout(
+    provider: &dyn EmbeddingProvider,
+    texts: &[TextToEmbed<'_>],
+    timeout: Duration,
+    executor: &BackgroundExecutor,
+) -> Result<Vec<Embedding>> {
+    let mut timer = executor.timer(timeout).fuse();
+    futures::select_biased! {
+        embeddings = provider.embed(texts).fuse() => embeddings,
+        () = timer => Err(anyhow!("timed out after {timeout:?} waiting for embeddings")),
+    }
+}
+
+/// Splits texts into consecutive batches of at most `max_batch_size` texts, whose estimated token
+/// counts add up to at most `max_batch_tokens`. A text that exceeds the token budget by itself is
+/// embedded in a batch of its own.
+pub(crate) fn embedding_batches<'a, 'b>(
+    texts: &'b [TextToEmbed<'a>],
+    max_batch_size: usize,
+    max_batch_tokens: Option<usize>,
+) -> Vec<&'b [TextToEmbed<'a>]> {
+    let max_batch_size = max_batch_size.max(1);
+    let max_batch_tokens = max_batch_tokens.unwrap_or(usize::MAX);
+    let mut batches = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_tokens = 0usize;
+    for (ix, text) in texts.iter().enumerate() {
+        let token_count = text.estimated_token_count();
+        let batch_len = ix - batch_start;
+        if batch_len > 0
+            && (batch_len == max_batch_size
+                || batch_tokens.saturating_add(token_count) > max_batch_tokens)
+        {
+            batches.push(&texts[batch_start..ix]);
+            batch_start = ix;
+            batch_tokens = 0;
+        }
+        batch_tokens += token_count;
+    }
+    if batch_start < texts.len() {
+        batches.push(&texts[batch_start..]);
+    }
+    batches
 }
 
 pub struct FakeEmbeddingProvider;
@@ -119,16 +230,100 @@ impl EmbeddingProvider for FakeEmbeddingProvider {
     fn batch_size(&self) -> usize {
         16
     }
+
+    fn dimension(&self) -> usize {
+        1536
+    }
+
+    fn truncation_dimensions(&self) -> &[usize] {
+        &[256, 512, 1024]
+    }
+
+    fn model_id(&self) -> &str {
+        "fake"
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::Arc;
 
     #[gpui::test]
     fn test_normalize_embedding() {
         let normalized = Embedding::new(vec![1.0, 1.0, 1.0]);
         let value: f32 = 1.0 / 3.0_f32.sqrt();
         assert_eq!(normalized, Embedding(vec![value; 3]));
+
+        let zero = Embedding::new(vec![0.0; 3]);
+        assert_eq!(zero, Embedding(vec![0.0; 3]));
+        assert_eq!(zero.similarity(&[normalized]), (0.0, 0));
+    }
+
+    #[gpui::test]
+    fn test_truncate_embedding() {
+        let embedding = Embedding::new(vec![3.0, 4.0, 12.0]);
+        let truncated = embedding.truncate(2);
+        assert_eq!(truncated.len(), 2);
+        assert!((truncated.0[0] - 0.6).abs() < 1e-6);
+        assert!((truncated.0[1] - 0.8).abs() < 1e-6);
+        assert_eq!(embedding.truncate(5), embedding);
+    }
+
+    #[gpui::test]
+    fn test_embedding_batches() {
+        let texts = [
+            "a".repeat(30),
+            "b".repeat(3),
+            "c".repeat(3),
+            "d".repeat(75),
+            "e".repeat(6),
+        ];
+        let texts = texts
+            .iter()
+            .map(|text| TextToEmbed::new(text))
+            .collect::<Vec<_>>();
+        let batch_lengths = |max_batch_size, max_batch_tokens| {
+            embedding_batches(&texts, max_batch_size, max_batch_tokens)
+                .iter()
+                .map(|batch| batch.len())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(batch_lengths(2, None), [2, 2, 1]);
+        // "a" is 10 tokens and "d" is 25, which exceeds the budget on its own.
+        assert_eq!(batch_lengths(16, Some(12)), [3, 1, 1]);
+        assert_eq!(batch_lengths(16, Some(10)), [1, 2, 1, 1]);
+        assert_eq!(batch_lengths(2, Some(12)), [2, 1, 1, 1]);
+        assert!(embedding_batches(&[], 16, Some(12)).is_empty());
+    }
+
+    #[gpui::test]
+    fn test_truncated_embedding_provider() {
+        let provider = Arc::new(FakeEmbeddingProvider);
+        assert!(TruncatedEmbeddingProvider::new(provider.clone(), 2048).is_err());
+        // Only dimensions that the model was trained to be truncated to are allowed.
+        let error = TruncatedEmbeddingProvider::new(provider.clone(), 300)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "fake doesn't support truncating embeddings to 300 dimensions"
+        );
+
+        let truncated_provider = TruncatedEmbeddingProvider::new(provider, 256).unwrap();
+        assert_eq!(truncated_provider.dimension(), 256);
+        assert_eq!(truncated_provider.model_id(), "fake@256");
+
+        let embeddings =
+            futures::executor::block_on(truncated_provider.embed(&[TextToEmbed::new("text")]))
+                .unwrap();
+        assert_eq!(embeddings[0].len(), 256);
+        let norm = embeddings[0]
+            .0
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>();
+        assert!((norm - 1.0).abs() < 1e-4);
     }
 }
@@ -0,0 +1,291 @@
+use crate::{Embedding, EmbeddingProvider, TextToEmbed};
+use anyhow::{Context as _, Result};
+use futures::{FutureExt, future::BoxFuture};
+use heed::types::{Bytes, SerdeBincode, Unit};
+use std::{mem, sync::Arc};
+use util::ResultExt as _;
+
+pub type EmbeddingCacheDb = heed::Database<SerdeBincode<[u8; 32]>, SerdeBincode<Embedding>>;
+
+/// Records when each cached embedding was last used, as a tick of a clock that advances whenever
+/// the cache is used.
+pub type EmbeddingCacheUsageDb = heed::Database<SerdeBincode<[u8; 32]>, SerdeBincode<u64>>;
+
+/// Orders the cached embeddings from least to most recently used. Each key is the big-endian tick
+/// of an embedding's last use followed by its digest, so that the least recently used embeddings
+/// can be evicted without reading the whole cache.
+pub type EmbeddingCacheLruDb = heed::Database<Bytes, Unit>;
+
+/// The prefix shared by the names of all embedding cache databases.
+pub(crate) const EMBEDDING_CACHE_DB_PREFIX: &str = "embedding-cache-";
+
+/// The approximate number of bytes of embeddings that a cache keeps by default. Caches share their
+/// memory map with every index, so they must not be allowed to grow without bound.
+const DEFAULT_MAX_CACHE_SIZE: usize = 128 * 1024 * 1024;
+
+/// Wraps an [`EmbeddingProvider`], persisting every document embedding it computes keyed by the
+/// digest of the embedded text, so that the same text is normally only sent to the provider once.
+/// Queries are always passed through, as models may embed them differently from documents with
+/// the same text.
+///
+/// Once the cache holds more than its maximum number of embeddings, the least recently used ones
+/// are evicted. Each model gets its own databases, which should only be used by one instance.
+pub struct CachingEmbeddingProvider {
+    provider: Arc<dyn EmbeddingProvider>,
+    db_connection: heed::Env,
+    db: EmbeddingCacheDb,
+    usage_db: EmbeddingCacheUsageDb,
+    lru_db: EmbeddingCacheLruDb,
+    max_entries: usize,
+}
+
+impl CachingEmbeddingProvider {
+    /// Opens the cache of the provider's model, creating its databases if they don't exist yet.
+    pub fn new(
+        provider: Arc<dyn EmbeddingProvider>,
+        db_connection: heed::Env,
+        txn: &mut heed::RwTxn,
+    ) -> Result<Self> {
+        let model_id = provider.model_id();
+        let db = db_connection
+            .create_database(txn, Some(&format!("{EMBEDDING_CACHE_DB_PREFIX}{model_id}")))?;
+        let usage_db = db_connection.create_database(
+            txn,
+            Some(&format!("{EMBEDDING_CACHE_DB_PREFIX}usage-{model_id}")),
+        )?;
+        let lru_db = db_connection.create_database(
+            txn,
+            Some(&format!("{EMBEDDING_CACHE_DB_PREFIX}lru-{model_id}")),
+        )?;
+        let embedding_size = provider.dimension().max(1) * mem::size_of::<f32>();
+        Ok(Self {
+            provider,
+            db_connection,
+            db,
+            usage_db,
+            lru_db,
+            max_entries: DEFAULT_MAX_CACHE_SIZE / embedding_size,
+        })
+    }
+
+    /// Sets the number of embeddings above which the least recently used ones are evicted.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    fn lru_key(tick: u64, digest: &[u8; 32]) -> [u8; 40] {
+        let mut key = [0; 40];
+        key[..8].copy_from_slice(&tick.to_be_bytes());
+        key[8..].copy_from_slice(digest);
+        key
+    }
+
+    /// Marks the embedding of the given digest as the most recently used one.
+    fn touch(&self, txn: &mut heed::RwTxn, digest: &[u8; 32], tick: u64) -> Result<()> {
+        if let Some(last_tick) = self.usage_db.get(txn, digest)? {
+            self.lru_db.delete(txn, &Self::lru_key(last_tick, digest))?;
+        }
+        self.usage_db.put(txn, digest, &tick)?;
+        self.lru_db.put(txn, &Self::lru_key(tick, digest), &())?;
+        Ok(())
+    }
+
+    fn update_cache(
+        &self,
+        used_digests: &[[u8; 32]],
+        texts: &[TextToEmbed],
+        embeddings: &[Embedding],
+    ) -> Result<()> {
+        let mut txn = self.db_connection.write_txn()?;
+        let tick = match self.lru_db.last(&txn)? {
+            Some((key, ())) => u64::from_be_bytes(key[..8].try_into()?) + 1,
+            None => 0,
+        };
+        for digest in used_digests {
+            self.touch(&mut txn, digest, tick)?;
+        }
+        for (text, embedding) in texts.iter().zip(embeddings) {
+            // Invalid embeddings are discarded by the index, so serving them later would only
+            // keep their files from ever being indexed.
+            if !text.is_query && embedding.len() == self.dimension() && embedding.is_finite() {
+                self.db.put(&mut txn, &text.digest, embedding)?;
+                self.touch(&mut txn, &text.digest, tick)?;
+            }
+        }
+
+        // Evict down to well below the limit, so that eviction doesn't run on every insert.
+        let len = self.db.len(&txn)? as usize;
+        if len > self.max_entries {
+            let evicted_count = len - self.max_entries * 3 / 4;
+            let mut evicted_keys = Vec::with_capacity(evicted_count);
+            for entry in self.lru_db.iter(&txn)?.take(evicted_count) {
+                let (key, ()) = entry?;
+                evicted_keys.push(key.to_vec());
+            }
+            for key in &evicted_keys {
+                let digest: [u8; 32] = key[8..].try_into()?;
+                self.db.delete(&mut txn, &digest)?;
+                self.usage_db.delete(&mut txn, &digest)?;
+                self.lru_db.delete(&mut txn, key)?;
+            }
+            log::debug!("evicted {} embeddings from cache", evicted_keys.len());
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+impl EmbeddingProvider for CachingEmbeddingProvider {
+    fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
+        async move {
+            let mut embeddings = Vec::with_capacity(texts.len());
+            let mut used_digests = Vec::new();
+            let mut uncached_texts = Vec::new();
+            {
+                let txn = self
+                    .db_connection
+                    .read_txn()
+                    .context("failed to create read transaction")?;
+                for text in texts {
+                    let embedding = if text.is_query {
+                        None
+                    } else {
+                        self.db.get(&txn, &text.digest)?
+                    };
+                    if embedding.is_some() {
+                        used_digests.push(text.digest);
+                    } else {
+                        uncached_texts.push(TextToEmbed {
+                            text: text.text,
+                            digest: text.digest,
+                            is_query: text.is_query,
+                        });
+                    }
+                    embeddings.push(embedding);
+                }
+            }
+
+            let mut new_embeddings = Vec::new();
+            if !uncached_texts.is_empty() {
+                new_embeddings = self.provider.embed(&uncached_texts).await?;
+                anyhow::ensure!(
+                    new_embeddings.len() == uncached_texts.len(),
+                    "embedding provider returned unexpected embedding count {}, expected {}",
+                    new_embeddings.len(),
+                    uncached_texts.len()
+                );
+            }
+            if !used_digests.is_empty() || uncached_texts.iter().any(|text| !text.is_query) {
+                self.update_cache(&used_digests, &uncached_texts, &new_embeddings)
+                    .log_err();
+            }
+
+            let mut new_embeddings = new_embeddings.into_iter();
+            for embedding in &mut embeddings {
+                if embedding.is_none() {
+                    *embedding = new_embeddings.next();
+                }
+            }
+
+            embeddings
+                .into_iter()
+                .map(|embedding| embedding.context("missing embedding"))
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.provider.batch_size()
+    }
+
+    fn max_batch_tokens(&self) -> Option<usize> {
+        self.provider.max_batch_tokens()
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        self.provider.max_input_tokens()
+    }
+
+    fn dimension(&self) -> usize {
+        self.provider.dimension()
+    }
+
+    fn truncation_dimensions(&self) -> &[usize] {
+        self.provider.truncation_dimensions()
+    }
+
+    fn model_id(&self) -> &str {
+        self.provider.model_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestEmbeddingProvider;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn test_caching_embedding_provider() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_connection = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(1024 * 1024)
+                .max_dbs(3)
+                .open(temp_dir.path())
+                .unwrap()
+        };
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let provider = Arc::new(TestEmbeddingProvider::new(16, 2, {
+            let embedded_texts = embedded_texts.clone();
+            move |text| {
+                embedded_texts.lock().push(text.to_string());
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            }
+        }));
+        let mut txn = db_connection.write_txn().unwrap();
+        let caching_provider =
+            CachingEmbeddingProvider::new(provider, db_connection.clone(), &mut txn)
+                .unwrap()
+                .with_max_entries(4);
+        txn.commit().unwrap();
+
+        let first = futures::executor::block_on(
+            caching_provider.embed(&[TextToEmbed::new("a"), TextToEmbed::new("bb")]),
+        )
+        .unwrap();
+        assert_eq!(*embedded_texts.lock(), ["a", "bb"]);
+
+        let second = futures::executor::block_on(
+            caching_provider.embed(&[TextToEmbed::new("ccc"), TextToEmbed::new("a")]),
+        )
+        .unwrap();
+        assert_eq!(*embedded_texts.lock(), ["a", "bb", "ccc"]);
+        assert_eq!(second[1], first[0]);
+        assert_eq!(second[0], Embedding::new(vec![3.0, 1.0]));
+
+        // Queries are never served from the cache, as they may be embedded differently.
+        futures::executor::block_on(caching_provider.embed(&[TextToEmbed::new_query("a")]))
+            .unwrap();
+        assert_eq!(*embedded_texts.lock(), ["a", "bb", "ccc", "a"]);
+
+        // Going over the limit evicts the least recently used embeddings, "bb" and "ccc".
+        futures::executor::block_on(caching_provider.embed(&[
+            TextToEmbed::new("a"),
+            TextToEmbed::new("dddd"),
+            TextToEmbed::new("eeeee"),
+        ]))
+        .unwrap();
+        embedded_texts.lock().clear();
+        futures::executor::block_on(caching_provider.embed(&[
+            TextToEmbed::new("a"),
+            TextToEmbed::new("bb"),
+            TextToEmbed::new("ccc"),
+        ]))
+        .unwrap();
+        assert_eq!(*embedded_texts.lock(), ["bb", "ccc"]);
+        db_connection.prepare_for_closing();
+    }
+}
@@ -32,6 +32,17 @@ impl LmStudioEmbeddingProvider {
     }
 }
 
+impl LmStudioEmbeddingModel {
+    /// Adds the instructions that the model was trained to expect before queries and documents.
+    fn prompt(&self, to_embed: &TextToEmbed) -> String {
+        let text = to_embed.text;
+        match (self, to_embed.is_query) {
+            (LmStudioEmbeddingModel::NomicEmbedText, true) => format!("search_query: {text}"),
+            (LmStudioEmbeddingModel::NomicEmbedText, false) => format!("search_document: {text}"),
+        }
+    }
+}
+
 impl EmbeddingProvider for LmStudioEmbeddingProvider {
     fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
         let model = match self.model {
@@ -41,7 +52,7 @@ impl EmbeddingProvider for LmStudioEmbeddingProvider {
         futures::future::try_join_all(texts.iter().map(|to_embed| {
             let request = LmStudioEmbeddingRequest {
                 model: model.to_string(),
-                prompt: to_embed.text.to_string(),
+                prompt: self.model.prompt(to_embed),
             };
 
             let request = serde_json::to_string(&request).unwrap();
@@ -67,4 +78,30 @@ impl EmbeddingProvider for LmStudioEmbeddingProvider {
     fn batch_size(&self) -> usize {
         256
     }
+
+    fn dimension(&self) -> usize {
+        match self.model {
+            LmStudioEmbeddingModel::NomicEmbedText => 768,
+        }
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        match self.model {
+            LmStudioEmbeddingModel::NomicEmbedText => Some(8192),
+        }
+    }
+
+    fn truncation_dimensions(&self) -> &[usize] {
+        match self.model {
+            // nomic-embed-text v1.5 was trained for these dimensions.
+            LmStudioEmbeddingModel::NomicEmbedText => &[64, 128, 256, 512],
+        }
+    }
+
+    fn model_id(&self) -> &str {
+        match self.model {
+            // Documents are embedded with a `search_document: ` prefix, which older indices lack.
+            LmStudioEmbeddingModel::NomicEmbedText => "lmstudio/nomic-embed-text/search-prefixed",
+        }
+    }
 }
@@ -33,18 +33,36 @@ impl OllamaEmbeddingProvider {
     }
 }
 
-impl EmbeddingProvider for OllamaEmbeddingProvider {
-    fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
-        //
-        let model = match self.model {
+impl OllamaEmbeddingModel {
+    fn name(&self) -> &'static str {
+        match self {
             OllamaEmbeddingModel::NomicEmbedText => "nomic-embed-text",
             OllamaEmbeddingModel::MxbaiEmbedLarge => "mxbai-embed-large",
-        };
+        }
+    }
+
+    /// Adds the instructions that the model was trained to expect before queries and documents.
+    fn prompt(&self, to_embed: &TextToEmbed) -> String {
+        let text = to_embed.text;
+        match (self, to_embed.is_query) {
+            (OllamaEmbeddingModel::NomicEmbedText, true) => format!("search_query: {text}"),
+            (OllamaEmbeddingModel::NomicEmbedText, false) => format!("search_document: {text}"),
+            (OllamaEmbeddingModel::MxbaiEmbedLarge, true) => {
+                format!("Represent this sentence for searching relevant passages: {text}")
+            }
+            (OllamaEmbeddingModel::MxbaiEmbedLarge, false) => text.to_string(),
+        }
+    }
+}
+
+impl EmbeddingProvider for OllamaEmbeddingProvider {
+    fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
+        let model = self.model.name();
 
         futures::future::try_join_all(texts.iter().map(|to_embed| {
             let request = OllamaEmbeddingRequest {
                 model: model.to_string(),
-                prompt: to_embed.text.to_string(),
+                prompt: self.model.prompt(to_embed),
             };
 
             let request = serde_json::to_string(&request).unwrap();
@@ -71,4 +89,71 @@ impl EmbeddingProvider for OllamaEmbeddingProvider {
         // TODO: Figure out decent value
         10
     }
+
+    fn dimension(&self) -> usize {
+        match self.model {
+            OllamaEmbeddingModel::NomicEmbedText => 768,
+            OllamaEmbeddingModel::MxbaiEmbedLarge => 1024,
+        }
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        match self.model {
+            OllamaEmbeddingModel::NomicEmbedText => Some(8192),
+            OllamaEmbeddingModel::MxbaiEmbedLarge => Some(512),
+        }
+    }
+
+    fn truncation_dimensions(&self) -> &[usize] {
+        match self.model {
+            // nomic-embed-text v1.5 was trained for these dimensions.
+            OllamaEmbeddingModel::NomicEmbedText => &[64, 128, 256, 512],
+            OllamaEmbeddingModel::MxbaiEmbedLarge => &[],
+        }
+    }
+
+    fn model_id(&self) -> &str {
+        match self.model {
+            // Documents are embedded with a `search_document: ` prefix, which older indices lack.
+            OllamaEmbeddingModel::NomicEmbedText => "ollama/nomic-embed-text/search-prefixed",
+            OllamaEmbeddingModel::MxbaiEmbedLarge => "ollama/mxbai-embed-large",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_client::FakeHttpClient;
+
+    #[test]
+    fn test_prompt() {
+        let document = TextToEmbed::new("fn main() {}");
+        let query = TextToEmbed::new_query("entry point");
+
+        let model = OllamaEmbeddingModel::NomicEmbedText;
+        assert_eq!(model.prompt(&document), "search_document: fn main() {}");
+        assert_eq!(model.prompt(&query), "search_query: entry point");
+
+        let model = OllamaEmbeddingModel::MxbaiEmbedLarge;
+        assert_eq!(model.prompt(&document), "fn main() {}");
+        assert_eq!(
+            model.prompt(&query),
+            "Represent this sentence for searching relevant passages: entry point"
+        );
+    }
+
+    #[test]
+    fn test_model_limits() {
+        let client = FakeHttpClient::with_404_response();
+
+        let provider =
+            OllamaEmbeddingProvider::new(client.clone(), OllamaEmbeddingModel::NomicEmbedText);
+        assert_eq!(provider.dimension(), 768);
+        assert_eq!(provider.max_input_tokens(), Some(8192));
+
+        let provider = OllamaEmbeddingProvider::new(client, OllamaEmbeddingModel::MxbaiEmbedLarge);
+        assert_eq!(provider.dimension(), 1024);
+        assert_eq!(provider.max_input_tokens(), Some(512));
+    }
 }
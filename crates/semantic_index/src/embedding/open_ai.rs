@@ -52,4 +52,37 @@ impl EmbeddingProvider for OpenAiEmbeddingProvider {
         // From https://platform.openai.com/docs/api-reference/embeddings/create
         2048
     }
+
+    fn max_batch_tokens(&self) -> Option<usize> {
+        // From https://platform.openai.com/docs/api-reference/embeddings/create
+        Some(300_000)
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        // From https://platform.openai.com/docs/guides/embeddings#embedding-models
+        Some(8191)
+    }
+
+    fn dimension(&self) -> usize {
+        // From https://platform.openai.com/docs/guides/embeddings#embedding-models
+        match self.model {
+            OpenAiEmbeddingModel::TextEmbedding3Small => 1536,
+            OpenAiEmbeddingModel::TextEmbedding3Large => 3072,
+        }
+    }
+
+    fn truncation_dimensions(&self) -> &[usize] {
+        // The text-embedding-3 models were trained so that their embeddings can be shortened.
+        match self.model {
+            OpenAiEmbeddingModel::TextEmbedding3Small => &[256, 512, 1024],
+            OpenAiEmbeddingModel::TextEmbedding3Large => &[256, 512, 1024, 1536],
+        }
+    }
+
+    fn model_id(&self) -> &str {
+        match self.model {
+            OpenAiEmbeddingModel::TextEmbedding3Small => "openai/text-embedding-3-small",
+            OpenAiEmbeddingModel::TextEmbedding3Large => "openai/text-embedding-3-large",
+        }
+    }
 }
@@ -0,0 +1,65 @@
+use crate::{Embedding, EmbeddingProvider, TextToEmbed};
+use anyhow::Result;
+use futures::{FutureExt, future::BoxFuture};
+use std::sync::Arc;
+
+/// Wraps an [`EmbeddingProvider`] whose model supports Matryoshka truncation, keeping only the
+/// leading dimensions of each embedding to make the index smaller. Only the dimensions listed in
+/// [`EmbeddingProvider::truncation_dimensions`] are allowed, as truncating the embeddings of other
+/// models discards most of their meaning.
+pub struct TruncatedEmbeddingProvider {
+    provider: Arc<dyn EmbeddingProvider>,
+    dimension: usize,
+    model_id: String,
+}
+
+impl TruncatedEmbeddingProvider {
+    pub fn new(provider: Arc<dyn EmbeddingProvider>, dimension: usize) -> Result<Self> {
+        anyhow::ensure!(
+            provider.truncation_dimensions().contains(&dimension),
+            "{} doesn't support truncating embeddings to {dimension} dimensions",
+            provider.model_id()
+        );
+        // Truncated embeddings can't be compared with full ones.
+        let model_id = format!("{}@{dimension}", provider.model_id());
+        Ok(Self {
+            provider,
+            dimension,
+            model_id,
+        })
+    }
+}
+
+impl EmbeddingProvider for TruncatedEmbeddingProvider {
+    fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
+        self.provider
+            .embed(texts)
+            .map(|embeddings| {
+                Ok(embeddings?
+                    .iter()
+                    .map(|embedding| embedding.truncate(self.dimension))
+                    .collect())
+            })
+            .boxed()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.provider.batch_size()
+    }
+
+    fn max_batch_tokens(&self) -> Option<usize> {
+        self.provider.max_batch_tokens()
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        self.provider.max_input_tokens()
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+}
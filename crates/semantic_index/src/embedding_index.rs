@@ -1,10 +1,13 @@
 use crate::{
-    chunking::{self, Chunk},
-    embedding::{Embedding, EmbeddingProvider, TextToEmbed},
+    chunking::{self, Chunk, ChunkingOptions},
+    embedding::{
+        ESTIMATED_BYTES_PER_TOKEN, Embedding, EmbeddingProvider, TextToEmbed, embed_with_timeout,
+        embedding_batches,
+    },
     indexing::{IndexingEntryHandle, IndexingEntrySet},
 };
 use anyhow::{Context as _, Result};
-use collections::Bound;
+use collections::{Bound, HashMap};
 use feature_flags::FeatureFlagAppExt;
 use fs::Fs;
 use fs::MTime;
@@ -12,15 +15,25 @@ use futures::{FutureExt as _, stream::StreamExt};
 use futures_batch::ChunksTimeoutStreamExt;
 use gpui::{App, AppContext as _, Entity, Task};
 use heed::types::{SerdeBincode, Str};
-use language::LanguageRegistry;
+use language::{LanguageRegistry, language_settings::all_language_settings};
 use log;
 use project::{Entry, UpdatedEntriesSet, Worktree};
 use serde::{Deserialize, Serialize};
 use smol::channel;
-use std::{cmp::Ordering, future::Future, iter, path::Path, pin::pin, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow, cmp::Ordering, future::Future, iter, path::Path, pin::pin, sync::Arc,
+    time::Duration,
+};
 use util::ResultExt;
 use worktree::Snapshot;
 
+/// The maximum number of embedded files to write in a single transaction.
+const PERSIST_BATCH_SIZE: usize = 64;
+
+/// Files larger than this are never loaded. Code this large is almost always generated, and
+/// reading it into memory on every change would be costly.
+pub(crate) const MAX_INDEXED_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
 pub struct EmbeddingIndex {
     worktree: Entity<Worktree>,
     db_connection: heed::Env,
@@ -28,6 +41,8 @@ pub struct EmbeddingIndex {
     fs: Arc<dyn Fs>,
     language_registry: Arc<LanguageRegistry>,
     embedding_provider: Arc<dyn EmbeddingProvider>,
+    chunking_options: ChunkingOptions,
+    embed_timeout: Duration,
     entry_ids_being_indexed: Arc<IndexingEntrySet>,
 }
 
@@ -39,6 +54,8 @@ impl EmbeddingIndex {
         embedding_db: heed::Database<Str, SerdeBincode<EmbeddedFile>>,
         language_registry: Arc<LanguageRegistry>,
         embedding_provider: Arc<dyn EmbeddingProvider>,
+        chunking_options: ChunkingOptions,
+        embed_timeout: Duration,
         entry_ids_being_indexed: Arc<IndexingEntrySet>,
     ) -> Self {
         Self {
@@ -48,6 +65,8 @@ impl EmbeddingIndex {
             db: embedding_db,
             language_registry,
             embedding_provider,
+            chunking_options,
+            embed_timeout,
             entry_ids_being_indexed,
         }
     }
@@ -68,7 +87,13 @@ impl EmbeddingIndex {
         let worktree_abs_path = worktree.abs_path().clone();
         let scan = self.scan_entries(worktree, cx);
         let chunk = self.chunk_files(worktree_abs_path, scan.updated_entries, cx);
-        let embed = Self::embed_files(self.embedding_provider.clone(), chunk.files, cx);
+        let embed = Self::embed_files(
+            self.embedding_provider.clone(),
+            chunk.files,
+            &self.chunking_options,
+            self.embed_timeout,
+            cx,
+        );
         let persist = self.persist_embeddings(scan.deleted_entry_ranges, embed.files, cx);
         async move {
             futures::try_join!(scan.task, chunk.task, embed.task, persist)?;
@@ -90,7 +115,13 @@ impl EmbeddingIndex {
         let worktree_abs_path = worktree.abs_path().clone();
         let scan = self.scan_updated_entries(worktree, updated_entries.clone(), cx);
         let chunk = self.chunk_files(worktree_abs_path, scan.updated_entries, cx);
-        let embed = Self::embed_files(self.embedding_provider.clone(), chunk.files, cx);
+        let embed = Self::embed_files(
+            self.embedding_provider.clone(),
+            chunk.files,
+            &self.chunking_options,
+            self.embed_timeout,
+            cx,
+        );
         let persist = self.persist_embeddings(scan.deleted_entry_ranges, embed.files, cx);
         async move {
             futures::try_join!(scan.task, chunk.task, embed.task, persist)?;
@@ -105,6 +136,7 @@ impl EmbeddingIndex {
         let db_connection = self.db_connection.clone();
         let db = self.db;
         let entries_being_indexed = self.entry_ids_being_indexed.clone();
+        let chunking_options = self.chunking_options.clone();
         let task = cx.background_spawn(async move {
             let txn = db_connection
                 .read_txn()
@@ -116,7 +148,12 @@ impl EmbeddingIndex {
                 .peekable();
 
             let mut deletion_range: Option<(Bound<&str>, Bound<&str>)> = None;
+            let is_file = |path: &Path| worktree.entry_for_path(path).is_some_and(Entry::is_file);
             for entry in worktree.files(false, 0) {
+                // Embeddings stored for a skipped file are deleted, like those of removed files.
+                if chunking_options.is_excluded(&entry.path, is_file) {
+                    continue;
+                }
                 log::trace!("scanning for embedding index: {:?}", &entry.path);
 
                 let entry_db_key = db_key_for_path(&entry.path);
@@ -188,6 +225,7 @@ impl EmbeddingIndex {
         let (updated_entries_tx, updated_entries_rx) = channel::bounded(512);
         let (deleted_entry_ranges_tx, deleted_entry_ranges_rx) = channel::bounded(128);
         let entries_being_indexed = self.entry_ids_being_indexed.clone();
+        let chunking_options = self.chunking_options.clone();
         let task = cx.background_spawn(async move {
             for (path, entry_id, status) in updated_entries.iter() {
                 match status {
@@ -195,7 +233,22 @@ impl EmbeddingIndex {
                     | project::PathChange::Updated
                     | project::PathChange::AddedOrUpdated => {
                         if let Some(entry) = worktree.entry_for_id(*entry_id) {
-                            if entry.is_file() {
+                            let is_file = |path: &Path| {
+                                worktree.entry_for_path(path).is_some_and(Entry::is_file)
+                            };
+                            let skipped =
+                                entry.is_ignored || chunking_options.is_excluded(path, is_file);
+                            if entry.is_file() && skipped {
+                                // A full scan skips these files, so remove any embeddings that
+                                // were stored before the file became ignored or was moved.
+                                let db_path = db_key_for_path(path);
+                                deleted_entry_ranges_tx
+                                    .send((
+                                        Bound::Included(db_path.clone()),
+                                        Bound::Included(db_path),
+                                    ))
+                                    .await?;
+                            } else if entry.is_file() {
                                 let handle = entries_being_indexed.insert(entry.id);
                                 updated_entries_tx.send((entry.clone(), handle)).await?;
                             }
@@ -231,6 +284,14 @@ impl EmbeddingIndex {
     ) -> ChunkFiles {
         let language_registry = self.language_registry.clone();
         let fs = self.fs.clone();
+        let db_connection = self.db_connection.clone();
+        let db = self.db;
+        let chunking_options = self.chunking_options.clone();
+        let max_input_size = self
+            .embedding_provider
+            .max_input_tokens()
+            .map(|max_input_tokens| max_input_tokens * ESTIMATED_BYTES_PER_TOKEN);
+        let user_file_types = all_language_settings(None, cx).file_types().clone();
         let (chunked_files_tx, chunked_files_rx) = channel::bounded(2048);
         let task = cx.spawn(async move |cx| {
             cx.background_executor()
@@ -238,18 +299,75 @@ impl EmbeddingIndex {
                     for _ in 0..cx.num_cpus() {
                         cx.spawn(async {
                             while let Ok((entry, handle)) = entries.recv().await {
+                                // Like generated files, large files are stored without any
+                                // chunks, so that they aren't considered again until they change.
+                                if entry.size > MAX_INDEXED_FILE_SIZE {
+                                    log::info!(
+                                        "not embedding {:?}, which is {} bytes",
+                                        entry.path,
+                                        entry.size
+                                    );
+                                    let chunked_file = ChunkedFile {
+                                        chunks: Vec::new(),
+                                        existing_embeddings: HashMap::default(),
+                                        handle,
+                                        path: entry.path,
+                                        mtime: entry.mtime,
+                                        text: String::new(),
+                                    };
+                                    if chunked_files_tx.send(chunked_file).await.is_err() {
+                                        return;
+                                    }
+                                    continue;
+                                }
+
                                 let entry_abs_path = worktree_abs_path.join(&entry.path);
                                 if let Some(text) = fs.load(&entry_abs_path).await.ok() {
                                     let language = language_registry
-                                        .language_for_file_path(&entry.path)
+                                        .language_for_file_path_and_content(
+                                            &entry.path,
+                                            Some(text.as_str()),
+                                            Some(&user_file_types),
+                                        )
                                         .await
                                         .ok();
-                                    let chunked_file = ChunkedFile {
-                                        chunks: chunking::chunk_text(
+                                    let existing_embeddings =
+                                        existing_embeddings(&db_connection, db, &entry.path)
+                                            .log_err()
+                                            .unwrap_or_default();
+                                    // Generated files are stored without any chunks, so that
+                                    // they aren't loaded again until they change.
+                                    let is_generated =
+                                        chunking::is_generated_file(&entry.path, &text);
+                                    let chunks = if is_generated {
+                                        log::info!("not embedding generated file {:?}", entry.path);
+                                        Vec::new()
+                                    } else if let Some(max_input_size) = max_input_size {
+                                        // Leave room for the header that will be embedded with
+                                        // each chunk, so the model doesn't truncate it.
+                                        let header_len = if chunking_options.path_context {
+                                            chunking::path_header(&entry.path).len()
+                                        } else {
+                                            0
+                                        };
+                                        chunking::chunk_text_with_max_size(
+                                            &text,
+                                            language.as_ref(),
+                                            &entry.path,
+                                            max_input_size.saturating_sub(header_len),
+                                            &chunking_options,
+                                        )
+                                    } else {
+                                        chunking::chunk_text(
                                             &text,
                                             language.as_ref(),
                                             &entry.path,
-                                        ),
+                                            &chunking_options,
+                                        )
+                                    };
+                                    let chunked_file = ChunkedFile {
+                                        chunks,
+                                        existing_embeddings,
                                         handle,
                                         path: entry.path,
                                         mtime: entry.mtime,
@@ -277,36 +395,83 @@ impl EmbeddingIndex {
     pub fn embed_files(
         embedding_provider: Arc<dyn EmbeddingProvider>,
         chunked_files: channel::Receiver<ChunkedFile>,
+        chunking_options: &ChunkingOptions,
+        embed_timeout: Duration,
         cx: &App,
     ) -> EmbedFiles {
         let embedding_provider = embedding_provider.clone();
+        let dimension = embedding_provider.dimension();
+        let chunking_options = chunking_options.clone();
         let (embedded_files_tx, embedded_files_rx) = channel::bounded(512);
+        let executor = cx.background_executor().clone();
         let task = cx.background_spawn(async move {
             let mut chunked_file_batches =
                 pin!(chunked_files.chunks_timeout(512, Duration::from_secs(2)));
             while let Some(chunked_files) = chunked_file_batches.next().await {
-                // View the batch of files as a vec of chunks
-                // Flatten out to a vec of chunks that we can subdivide into batch sized pieces
-                // Once those are done, reassemble them back into the files in which they belong
-                // If any embeddings fail for a file, the entire file is discarded
-
-                let chunks: Vec<TextToEmbed> = chunked_files
+                // Flatten the batch of files into a vec of chunks that we can subdivide into
+                // batch sized pieces, then reassemble the embeddings into the files in which they
+                // belong. If any embeddings fail for a file, the entire file is discarded.
+                let chunk_texts = chunked_files
                     .iter()
                     .flat_map(|file| {
-                        file.chunks.iter().map(|chunk| TextToEmbed {
-                            text: &file.text[chunk.range.clone()],
-                            digest: chunk.digest,
-                        })
+                        file.chunks
+                            .iter()
+                            // Chunks that haven't changed since the file was last indexed reuse
+                            // their stored embedding.
+                            .filter(|chunk| !file.existing_embeddings.contains_key(&chunk.digest))
+                            .map(|chunk| (chunk, &file.text[chunk.range.clone()]))
+                            .filter(|(_, text)| !text.trim().is_empty())
+                            .map(|(chunk, text)| {
+                                let text = chunking_options.embedded_text(text, Some(&file.path));
+                                // When the embedded text differs from the chunk, so does its
+                                // digest.
+                                let digest =
+                                    matches!(text, Cow::Borrowed(_)).then_some(chunk.digest);
+                                (text, digest)
+                            })
                     })
                     .collect::<Vec<_>>();
+                let chunks: Vec<TextToEmbed> = chunk_texts
+                    .iter()
+                    .map(|(text, digest)| match digest {
+                        Some(digest) => TextToEmbed {
+                            text,
+                            digest: *digest,
+                            is_query: false,
+                        },
+                        None => TextToEmbed::new(text),
+                    })
+                    .collect();
 
                 let mut embeddings: Vec<Option<Embedding>> = Vec::new();
-                for embedding_batch in chunks.chunks(embedding_provider.batch_size()) {
-                    if let Some(batch_embeddings) =
-                        embedding_provider.embed(embedding_batch).await.log_err()
-                    {
+                let batches = embedding_batches(
+                    &chunks,
+                    embedding_provider.batch_size(),
+                    embedding_provider.max_batch_tokens(),
+                );
+                for embedding_batch in batches {
+                    let batch_embeddings = embed_with_timeout(
+                        embedding_provider.as_ref(),
+                        embedding_batch,
+                        embed_timeout,
+                        &executor,
+                    )
+                    .await;
+                    if let Some(batch_embeddings) = batch_embeddings.log_err() {
                         if batch_embeddings.len() == embedding_batch.len() {
-                            embeddings.extend(batch_embeddings.into_iter().map(Some));
+                            embeddings.extend(batch_embeddings.into_iter().map(|embedding| {
+                                // Embeddings of the wrong dimension or with non-finite values
+                                // can't be compared with others, so they count as failures.
+                                if embedding.len() == dimension && embedding.is_finite() {
+                                    Some(embedding)
+                                } else {
+                                    log::warn!(
+                                        "discarding invalid {}-dimensional embedding",
+                                        embedding.len()
+                                    );
+                                    None
+                                }
+                            }));
                             continue;
                         }
                         log::error!(
@@ -327,9 +492,21 @@ impl EmbeddingIndex {
                     };
 
                     let mut embedded_all_chunks = true;
-                    for (chunk, embedding) in
-                        chunked_file.chunks.into_iter().zip(embeddings.by_ref())
-                    {
+                    let mut reused_count = 0;
+                    let mut embedded_count = 0;
+                    for chunk in chunked_file.chunks {
+                        let existing_embedding =
+                            chunked_file.existing_embeddings.get(&chunk.digest);
+                        let embedding = if let Some(embedding) = existing_embedding {
+                            reused_count += 1;
+                            Some(embedding.clone())
+                        } else if chunked_file.text[chunk.range.clone()].trim().is_empty() {
+                            // Some providers reject empty input, so blank chunks are never sent.
+                            Some(Embedding::new(vec![0.; dimension]))
+                        } else {
+                            embedded_count += 1;
+                            embeddings.next().flatten()
+                        };
                         if let Some(embedding) = embedding {
                             embedded_file
                                 .chunks
@@ -339,7 +516,15 @@ impl EmbeddingIndex {
                         }
                     }
 
+                    log::debug!(
+                        "reused {} of {} embeddings for file {:?}",
+                        reused_count,
+                        embedded_file.chunks.len(),
+                        embedded_file.path
+                    );
+
                     if embedded_all_chunks {
+                        chunked_file.handle.record_embedded_chunks(embedded_count);
                         embedded_files_tx
                             .send((embedded_file, chunked_file.handle))
                             .await?;
@@ -366,7 +551,8 @@ impl EmbeddingIndex {
 
         cx.background_spawn(async move {
             let mut deleted_entry_ranges = pin!(deleted_entry_ranges);
-            let mut embedded_files = pin!(embedded_files);
+            // Commit whichever files are ready together, as each commit waits for the disk.
+            let mut embedded_files = pin!(embedded_files.ready_chunks(PERSIST_BATCH_SIZE));
             loop {
                 // Interleave deletions and persists of embedded files
                 futures::select_biased! {
@@ -380,12 +566,14 @@ impl EmbeddingIndex {
                             txn.commit()?;
                         }
                     },
-                    file = embedded_files.next() => {
-                        if let Some((file, _)) = file {
+                    files = embedded_files.next() => {
+                        if let Some(files) = files {
                             let mut txn = db_connection.write_txn()?;
-                            log::debug!("saving embedding for file {:?}", file.path);
-                            let key = db_key_for_path(&file.path);
-                            db.put(&mut txn, &key, &file)?;
+                            for (file, _) in &files {
+                                log::debug!("saving embedding for file {:?}", file.path);
+                                let key = db_key_for_path(&file.path);
+                                db.put(&mut txn, &key, file)?;
+                            }
                             txn.commit()?;
                         }
                     },
@@ -446,6 +634,8 @@ pub struct ChunkedFile {
     pub handle: IndexingEntryHandle,
     pub text: String,
     pub chunks: Vec<Chunk>,
+    /// Embeddings stored the last time this file was indexed, keyed by chunk digest.
+    pub existing_embeddings: HashMap<[u8; 32], Embedding>,
 }
 
 pub struct EmbedFiles {
@@ -466,6 +656,491 @@ pub struct EmbeddedChunk {
     pub embedding: Embedding,
 }
 
-fn db_key_for_path(path: &Arc<Path>) -> String {
+/// The version of the format in which [`EmbeddedFile`]s are stored. Bump it whenever that format
+/// changes, so that indices written in the old format are rebuilt instead of failing to load.
+const EMBEDDING_SCHEMA_VERSION: &str = "1";
+
+/// The keys under which an index's metadata database stores the format of its embedded files, the
+/// id of the model that produced their embeddings and their dimension, and the options that shaped
+/// the embedded text.
+pub(crate) const SCHEMA_VERSION_KEY: &str = "schema_version";
+const EMBEDDING_MODEL_KEY: &str = "model_id";
+const EMBEDDING_DIMENSION_KEY: &str = "dimension";
+const EMBEDDED_TEXT_KEY: &str = "embedded_text";
+
+/// Records the current schema version, `model_id`, `dimension` and embedded text options for the
+/// embeddings in `db`. If they were stored in another format, produced by a different model, have
+/// a different dimension or were computed from differently prepared text, clears `db` first so
+/// that the worktree is indexed again from scratch, as such embeddings can't be compared with new
+/// ones. Embeddings stored without this metadata can't be trusted either, so they're cleared too.
+///
+/// Returns whether `db` was cleared.
+pub fn reset_stale_embeddings(
+    txn: &mut heed::RwTxn,
+    db: heed::Database<Str, SerdeBincode<EmbeddedFile>>,
+    metadata_db: heed::Database<Str, Str>,
+    model_id: &str,
+    dimension: usize,
+    chunking_options: &ChunkingOptions,
+) -> Result<bool> {
+    let has_embeddings = !db.is_empty(txn)?;
+    let dimension = dimension.to_string();
+    let embedded_text_options = chunking_options.embedded_text_options();
+    let mut stale = false;
+    for (key, value) in [
+        (SCHEMA_VERSION_KEY, EMBEDDING_SCHEMA_VERSION),
+        (EMBEDDING_MODEL_KEY, model_id),
+        (EMBEDDING_DIMENSION_KEY, dimension.as_str()),
+        (EMBEDDED_TEXT_KEY, embedded_text_options.as_str()),
+    ] {
+        let stored_value = metadata_db.get(txn, key)?.map(str::to_owned);
+        let changed = match &stored_value {
+            Some(stored_value) => stored_value != value,
+            None => has_embeddings,
+        };
+        if changed {
+            log::info!(
+                "embedding {key} changed from {stored_value:?} to {value:?}, \
+                clearing embeddings so they can be recomputed"
+            );
+            stale = true;
+        }
+        if stored_value.as_deref() != Some(value) {
+            metadata_db.put(txn, key, value)?;
+        }
+    }
+    if stale {
+        db.clear(txn)?;
+    }
+    Ok(stale)
+}
+
+fn existing_embeddings(
+    db_connection: &heed::Env,
+    db: heed::Database<Str, SerdeBincode<EmbeddedFile>>,
+    path: &Arc<Path>,
+) -> Result<HashMap<[u8; 32], Embedding>> {
+    let txn = db_connection
+        .read_txn()
+        .context("failed to create read transaction")?;
+    let Some(embedded_file) = db.get(&txn, &db_key_for_path(path))? else {
+        return Ok(HashMap::default());
+    };
+    Ok(embedded_file
+        .chunks
+        .into_iter()
+        .map(|embedded_chunk| (embedded_chunk.chunk.digest, embedded_chunk.embedding))
+        .collect())
+}
+
+pub(crate) fn db_key_for_path(path: &Arc<Path>) -> String {
     path.to_string_lossy().replace('/', "\0")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        embedding::DEFAULT_EMBED_TIMEOUT,
+        tests::{HangingEmbeddingProvider, TestEmbeddingProvider},
+    };
+    use gpui::TestAppContext;
+    use parking_lot::Mutex;
+    use project::ProjectEntryId;
+    use std::ops::Range;
+
+    /// Creates a file to embed whose chunks cover the given ranges of its text.
+    fn chunked_file(
+        path: &str,
+        text: &str,
+        ranges: impl IntoIterator<Item = Range<usize>>,
+        handle: IndexingEntryHandle,
+    ) -> ChunkedFile {
+        ChunkedFile {
+            path: Path::new(path).into(),
+            mtime: None,
+            handle,
+            text: text.to_string(),
+            chunks: ranges
+                .into_iter()
+                .map(|range| Chunk {
+                    digest: TextToEmbed::new(&text[range.clone()]).digest,
+                    range,
+                })
+                .collect(),
+            existing_embeddings: HashMap::default(),
+        }
+    }
+
+    /// Embeds the given files, returning the ones whose chunks were all embedded.
+    async fn embed_chunked_files(
+        provider: Arc<dyn EmbeddingProvider>,
+        files: Vec<ChunkedFile>,
+        chunking_options: &ChunkingOptions,
+        cx: &mut TestAppContext,
+    ) -> Vec<EmbeddedFile> {
+        let (chunked_files_tx, chunked_files_rx) = channel::unbounded();
+        for file in files {
+            chunked_files_tx.send_blocking(file).unwrap();
+        }
+        chunked_files_tx.close();
+
+        let embed_files_task = cx.update(|cx| {
+            EmbeddingIndex::embed_files(
+                provider,
+                chunked_files_rx,
+                chunking_options,
+                DEFAULT_EMBED_TIMEOUT,
+                cx,
+            )
+        });
+        embed_files_task.task.await.unwrap();
+
+        let mut embedded_files = Vec::new();
+        while let Ok((embedded_file, _)) = embed_files_task.files.recv().await {
+            embedded_files.push(embedded_file);
+        }
+        embedded_files
+    }
+
+    fn indexing_entries() -> Arc<IndexingEntrySet> {
+        let (indexing_progress_tx, _) = channel::unbounded();
+        Arc::new(IndexingEntrySet::new(indexing_progress_tx))
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_discards_invalid_embeddings(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        // Both files are embedded in one batch, but only one gets a valid embedding.
+        let provider = Arc::new(TestEmbeddingProvider::new(8, 2, |text| {
+            if text.contains("nan") {
+                Ok(Embedding::new(vec![f32::NAN, 1.0]))
+            } else {
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            }
+        }));
+
+        let indexing_entries = indexing_entries();
+        let files = [("valid.md", "valid text"), ("invalid.md", "nan text")]
+            .into_iter()
+            .enumerate()
+            .map(|(ix, (path, text))| {
+                let handle = indexing_entries.insert(ProjectEntryId::from_proto(ix as u64));
+                chunked_file(path, text, [0..text.len()], handle)
+            })
+            .collect();
+        let embedded_files =
+            embed_chunked_files(provider, files, &ChunkingOptions::default(), cx).await;
+
+        assert_eq!(embedded_files.len(), 1);
+        assert_eq!(embedded_files[0].path.as_ref(), Path::new("valid.md"));
+        assert!(embedded_files[0].chunks[0].embedding.is_finite());
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_times_out(cx: &mut TestAppContext) {
+        let indexing_entries = indexing_entries();
+        let text = "fn main() {}\n";
+        let (chunked_files_tx, chunked_files_rx) = channel::unbounded::<ChunkedFile>();
+        chunked_files_tx
+            .send_blocking(chunked_file(
+                "main.rs",
+                text,
+                [0..text.len()],
+                indexing_entries.insert(ProjectEntryId::from_proto(0)),
+            ))
+            .unwrap();
+        chunked_files_tx.close();
+
+        let embed_files_task = cx.update(|cx| {
+            EmbeddingIndex::embed_files(
+                Arc::new(HangingEmbeddingProvider),
+                chunked_files_rx,
+                &ChunkingOptions::default(),
+                Duration::from_secs(5),
+                cx,
+            )
+        });
+        cx.run_until_parked();
+        cx.executor().advance_clock(Duration::from_secs(5));
+
+        // The file fails to embed, rather than blocking the task forever.
+        embed_files_task.task.await.unwrap();
+        assert!(embed_files_task.files.recv().await.is_err());
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_reuses_unchanged_chunks(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let compute_embedding = |text: &str| {
+            Embedding::new(
+                ('a'..='z')
+                    .map(|char| text.chars().filter(|c| *c == char).count() as f32)
+                    .collect(),
+            )
+        };
+        let embedded_texts = Arc::new(Mutex::new(Vec::<String>::new()));
+        let provider = Arc::new(TestEmbeddingProvider::new(3, 26, {
+            let embedded_texts = embedded_texts.clone();
+            move |text| {
+                embedded_texts.lock().push(text.to_string());
+                Ok(compute_embedding(text))
+            }
+        }));
+
+        // Simulate an edit to the middle line of a file that was already indexed.
+        let old_text = "abcd\nefgh\nijkl\n";
+        let new_text = "abcd\nefxh\nijkl\n";
+        let ranges = [0..5, 5..10, 10..15];
+        let indexing_entries = indexing_entries();
+        let mut file = chunked_file(
+            "test.md",
+            new_text,
+            ranges.clone(),
+            indexing_entries.insert(ProjectEntryId::from_proto(0)),
+        );
+        file.existing_embeddings = ranges
+            .iter()
+            .map(|range| {
+                let text = &old_text[range.clone()];
+                (TextToEmbed::new(text).digest, compute_embedding(text))
+            })
+            .collect();
+        let embedded_files =
+            embed_chunked_files(provider, vec![file], &ChunkingOptions::default(), cx).await;
+
+        assert_eq!(*embedded_texts.lock(), vec!["efxh\n".to_string()]);
+        assert_eq!(
+            embedded_files[0]
+                .chunks
+                .iter()
+                .map(|embedded_chunk| embedded_chunk.embedding.clone())
+                .collect::<Vec<Embedding>>(),
+            vec![
+                compute_embedding("abcd\n"),
+                compute_embedding("efxh\n"),
+                compute_embedding("ijkl\n"),
+            ],
+        );
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_with_blank_chunks(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let provider = Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
+            anyhow::ensure!(!text.trim().is_empty(), "cannot embed blank text");
+            Ok(Embedding::new(vec![1.0, 1.0]))
+        }));
+
+        let indexing_entries = indexing_entries();
+        let file = chunked_file(
+            "test.md",
+            "abcd\n  \n\nefgh\n",
+            [0..5, 5..9, 9..14],
+            indexing_entries.insert(ProjectEntryId::from_proto(0)),
+        );
+        let embedded_files =
+            embed_chunked_files(provider, vec![file], &ChunkingOptions::default(), cx).await;
+
+        assert_eq!(
+            embedded_files[0]
+                .chunks
+                .iter()
+                .map(|embedded_chunk| embedded_chunk.embedding.clone())
+                .collect::<Vec<Embedding>>(),
+            vec![
+                Embedding::new(vec![1.0, 1.0]),
+                Embedding::new(vec![0.0, 0.0]),
+                Embedding::new(vec![1.0, 1.0]),
+            ],
+        );
+        assert_eq!(indexing_entries.embedded_chunk_count(), 2);
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_with_path_context(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let provider = Arc::new(TestEmbeddingProvider::new(16, 3, |text| {
+            let count = |char| text.chars().filter(|c| *c == char).count() as f32;
+            Ok(Embedding::new(vec![count('a'), count('b'), 1.0]))
+        }));
+
+        let indexing_entries = indexing_entries();
+        let files = || {
+            let text = "fn new() {}\n";
+            ["a.rs", "b.rs"]
+                .into_iter()
+                .enumerate()
+                .map(|(ix, path)| {
+                    let handle = indexing_entries.insert(ProjectEntryId::from_proto(ix as u64));
+                    chunked_file(path, text, [0..text.len()], handle)
+                })
+                .collect::<Vec<_>>()
+        };
+        let embeddings = |embedded_files: Vec<EmbeddedFile>| {
+            embedded_files
+                .into_iter()
+                .map(|embedded_file| embedded_file.chunks[0].embedding.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let embedded_files =
+            embed_chunked_files(provider.clone(), files(), &ChunkingOptions::default(), cx).await;
+        let embeddings_without_context = embeddings(embedded_files);
+        assert_eq!(embeddings_without_context[0], embeddings_without_context[1]);
+
+        let chunking_options = ChunkingOptions {
+            path_context: true,
+            ..Default::default()
+        };
+        let embedded_files = embed_chunked_files(provider, files(), &chunking_options, cx).await;
+        let embeddings_with_context = embeddings(embedded_files);
+        assert_eq!(
+            embeddings_with_context[0],
+            Embedding::new(vec![1.0, 0.0, 1.0])
+        );
+        assert_eq!(
+            embeddings_with_context[1],
+            Embedding::new(vec![0.0, 1.0, 1.0])
+        );
+    }
+
+    #[gpui::test]
+    async fn test_embed_files_with_normalized_whitespace(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let provider = Arc::new(TestEmbeddingProvider::new(16, 2, {
+            let embedded_texts = embedded_texts.clone();
+            move |text| {
+                embedded_texts.lock().push(text.to_string());
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            }
+        }));
+
+        let indexing_entries = indexing_entries();
+        let text = "fn main() {\n\n    println!(\"hi\");\n}\n";
+        let file = chunked_file(
+            "main.rs",
+            text,
+            [0..text.len()],
+            indexing_entries.insert(ProjectEntryId::from_proto(0)),
+        );
+        let chunking_options = ChunkingOptions {
+            normalize_whitespace: true,
+            ..Default::default()
+        };
+        let embedded_files = embed_chunked_files(provider, vec![file], &chunking_options, cx).await;
+
+        assert_eq!(
+            embedded_texts.lock().as_slice(),
+            ["fn main() { println!(\"hi\"); }"]
+        );
+        // The stored chunk still covers the text as it appears in the file.
+        assert_eq!(embedded_files[0].chunks[0].chunk.range, 0..text.len());
+        assert_eq!(
+            embedded_files[0].chunks[0].chunk.digest,
+            TextToEmbed::new(text).digest
+        );
+    }
+
+    #[test]
+    fn test_reset_stale_embeddings() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_connection = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(1024 * 1024)
+                .max_dbs(2)
+                .open(temp_dir.path())
+                .unwrap()
+        };
+        let mut txn = db_connection.write_txn().unwrap();
+        let db = db_connection
+            .create_database(&mut txn, Some("embeddings"))
+            .unwrap();
+        let metadata_db = db_connection
+            .create_database(&mut txn, Some("embedding-metadata"))
+            .unwrap();
+        let embedded_file = EmbeddedFile {
+            path: Path::new("main.rs").into(),
+            mtime: None,
+            chunks: vec![EmbeddedChunk {
+                chunk: Chunk {
+                    range: 0..12,
+                    digest: Default::default(),
+                },
+                embedding: Embedding::new(vec![1.0, 0.0]),
+            }],
+        };
+
+        let options = ChunkingOptions::default();
+
+        // The first model to index an empty worktree is recorded.
+        assert!(
+            !reset_stale_embeddings(&mut txn, db, metadata_db, "model-a", 2, &options).unwrap()
+        );
+        assert_eq!(metadata_db.get(&txn, "model_id").unwrap(), Some("model-a"));
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+
+        // Loading the index with the same model keeps its embeddings.
+        assert!(
+            !reset_stale_embeddings(&mut txn, db, metadata_db, "model-a", 2, &options).unwrap()
+        );
+        assert_eq!(db.len(&txn).unwrap(), 1);
+
+        // Switching models clears them, even though the dimension is unchanged.
+        assert!(reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &options).unwrap());
+        assert!(db.is_empty(&txn).unwrap());
+        assert!(
+            !reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &options).unwrap()
+        );
+
+        // So does switching to embeddings of another dimension.
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+        assert!(reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 3, &options).unwrap());
+        assert!(db.is_empty(&txn).unwrap());
+        reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &options).unwrap();
+
+        // Embeddings stored in an older format are cleared too.
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+        metadata_db.put(&mut txn, SCHEMA_VERSION_KEY, "0").unwrap();
+        assert!(reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &options).unwrap());
+        assert!(db.is_empty(&txn).unwrap());
+        assert_ne!(
+            metadata_db.get(&txn, SCHEMA_VERSION_KEY).unwrap(),
+            Some("0")
+        );
+
+        // So are embeddings that were stored without any metadata.
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+        metadata_db.clear(&mut txn).unwrap();
+        assert!(reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &options).unwrap());
+        assert!(db.is_empty(&txn).unwrap());
+
+        // And embeddings of text that was prepared with different options.
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+        let path_context = ChunkingOptions {
+            path_context: true,
+            ..options
+        };
+        assert!(
+            reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &path_context).unwrap()
+        );
+        assert!(db.is_empty(&txn).unwrap());
+        db.put(&mut txn, "main.rs", &embedded_file).unwrap();
+        let normalized = ChunkingOptions {
+            normalize_whitespace: true,
+            ..path_context
+        };
+        assert!(
+            reset_stale_embeddings(&mut txn, db, metadata_db, "model-b", 2, &normalized).unwrap()
+        );
+        assert!(db.is_empty(&txn).unwrap());
+        txn.commit().unwrap();
+        db_connection.prepare_for_closing();
+    }
+}
@@ -2,11 +2,16 @@ use collections::HashSet;
 use parking_lot::Mutex;
 use project::ProjectEntryId;
 use smol::channel;
-use std::sync::{Arc, Weak};
+use std::sync::{
+    Arc, Weak,
+    atomic::{AtomicUsize, Ordering},
+};
 
 /// The set of entries that are currently being indexed.
 pub struct IndexingEntrySet {
     entry_ids: Mutex<HashSet<ProjectEntryId>>,
+    queued_count: AtomicUsize,
+    embedded_chunk_count: AtomicUsize,
     tx: channel::Sender<()>,
 }
 
@@ -21,12 +26,16 @@ impl IndexingEntrySet {
     pub fn new(tx: channel::Sender<()>) -> Self {
         Self {
             entry_ids: Default::default(),
+            queued_count: AtomicUsize::new(0),
+            embedded_chunk_count: AtomicUsize::new(0),
             tx,
         }
     }
 
     pub fn insert(self: &Arc<Self>, entry_id: ProjectEntryId) -> IndexingEntryHandle {
-        self.entry_ids.lock().insert(entry_id);
+        if self.entry_ids.lock().insert(entry_id) {
+            self.queued_count.fetch_add(1, Ordering::Relaxed);
+        }
         self.tx.send_blocking(()).ok();
         IndexingEntryHandle {
             entry_id,
@@ -37,6 +46,24 @@ impl IndexingEntrySet {
     pub fn len(&self) -> usize {
         self.entry_ids.lock().len()
     }
+
+    /// The total number of entries that have been queued for indexing in this set.
+    pub fn queued_count(&self) -> usize {
+        self.queued_count.load(Ordering::Relaxed)
+    }
+
+    /// The total number of chunks that have been embedded for entries in this set.
+    pub fn embedded_chunk_count(&self) -> usize {
+        self.embedded_chunk_count.load(Ordering::Relaxed)
+    }
+}
+
+impl IndexingEntryHandle {
+    pub fn record_embedded_chunks(&self, count: usize) {
+        if let Some(set) = self.set.upgrade() {
+            set.embedded_chunk_count.fetch_add(count, Ordering::Relaxed);
+        }
+    }
 }
 
 impl Drop for IndexingEntryHandle {
@@ -1,10 +1,12 @@
 use crate::{
-    embedding::{EmbeddingProvider, TextToEmbed},
+    chunking::ChunkingOptions,
+    embedding::{EmbeddingProvider, TextToEmbed, embed_with_timeout},
+    embedding_index::db_key_for_path,
     summary_index::FileSummary,
     worktree_index::{WorktreeIndex, WorktreeIndexHandle},
 };
 use anyhow::{Context as _, Result, anyhow};
-use collections::HashMap;
+use collections::{HashMap, HashSet};
 use fs::Fs;
 use futures::FutureExt;
 use gpui::{
@@ -12,7 +14,7 @@ use gpui::{
 };
 use language::LanguageRegistry;
 use log;
-use project::{Project, Worktree, WorktreeId};
+use project::{Project, ProjectPath, Worktree, WorktreeId};
 use serde::{Deserialize, Serialize};
 use smol::channel;
 use std::{
@@ -22,6 +24,7 @@ use std::{
     ops::{Range, RangeInclusive},
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use util::ResultExt;
 
@@ -49,13 +52,41 @@ pub struct WorktreeSearchResult {
     pub range: Range<usize>,
     pub query_index: usize,
     pub score: f32,
+    /// The digest of the chunk's content.
+    pub digest: [u8; 32],
+}
+
+/// Options for [`ProjectIndex::search_with_options`].
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// Restricts the search to chunks of the file at this path.
+    pub path: Option<ProjectPath>,
+    /// Skips chunks of this file that overlap this range.
+    pub excluded: Option<(ProjectPath, Range<usize>)>,
+    /// The number of best results to skip, so that results can be fetched page by page.
+    pub offset: usize,
+    /// The maximum number of results to return.
+    pub limit: usize,
+    /// Whether the queries are code to embed like the indexed chunks, rather than like queries.
+    /// The code is treated as part of the file in `excluded`, or else the one in `path`, when
+    /// its header is embedded with it.
+    pub queries_are_code: bool,
+    /// Whether to return only the best-scoring of several chunks with identical content, such as
+    /// the same code in copies of a file.
+    pub dedup_results: bool,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Status {
     Idle,
     Loading,
-    Scanning { remaining_count: NonZeroUsize },
+    Scanning {
+        remaining_count: NonZeroUsize,
+        /// The number of files queued for indexing since the scan started.
+        total_count: usize,
+        /// The number of chunks embedded since the scan started.
+        embedded_chunk_count: usize,
+    },
 }
 
 pub struct ProjectIndex {
@@ -65,8 +96,12 @@ pub struct ProjectIndex {
     language_registry: Arc<LanguageRegistry>,
     fs: Arc<dyn Fs>,
     last_status: Status,
+    scan_start_queued_count: usize,
+    scan_start_embedded_chunk_count: usize,
     status_tx: channel::Sender<()>,
     embedding_provider: Arc<dyn EmbeddingProvider>,
+    chunking_options: ChunkingOptions,
+    embed_timeout: Duration,
     _maintain_status: Task<()>,
     _subscription: Subscription,
 }
@@ -76,6 +111,8 @@ impl ProjectIndex {
         project: Entity<Project>,
         db_connection: heed::Env,
         embedding_provider: Arc<dyn EmbeddingProvider>,
+        chunking_options: ChunkingOptions,
+        embed_timeout: Duration,
         cx: &mut Context<Self>,
     ) -> Self {
         let language_registry = project.read(cx).languages().clone();
@@ -89,7 +126,11 @@ impl ProjectIndex {
             fs,
             status_tx,
             last_status: Status::Idle,
+            scan_start_queued_count: 0,
+            scan_start_embedded_chunk_count: 0,
             embedding_provider,
+            chunking_options,
+            embed_timeout,
             _subscription: cx.subscribe(&project, Self::handle_project_event),
             _maintain_status: cx.spawn(async move |this, cx| {
                 while status_rx.recv().await.is_ok() {
@@ -157,6 +198,8 @@ impl ProjectIndex {
                     self.fs.clone(),
                     self.status_tx.clone(),
                     self.embedding_provider.clone(),
+                    self.chunking_options.clone(),
+                    self.embed_timeout,
                     cx,
                 );
 
@@ -195,8 +238,35 @@ impl ProjectIndex {
         self.update_status(cx);
     }
 
+    /// Drops the index of the worktree at the given path, if the project has it open, and loads it
+    /// again so that the worktree is scanned against what's currently stored for it.
+    pub(crate) fn reload_worktree_index(
+        &mut self,
+        worktree_abs_path: &Path,
+        cx: &mut Context<Self>,
+    ) {
+        let Some(project) = self.project.upgrade() else {
+            return;
+        };
+        let worktree_ids = project
+            .read(cx)
+            .worktrees(cx)
+            .filter(|worktree| worktree.read(cx).abs_path().as_ref() == worktree_abs_path)
+            .map(|worktree| worktree.entity_id())
+            .collect::<Vec<_>>();
+        if worktree_ids.is_empty() {
+            return;
+        }
+        for worktree_id in worktree_ids {
+            self.worktree_indices.remove(&worktree_id);
+        }
+        self.update_worktree_indices(cx);
+    }
+
     fn update_status(&mut self, cx: &mut Context<Self>) {
         let mut indexing_count = 0;
+        let mut queued_count = 0;
+        let mut embedded_chunk_count = 0;
         let mut any_loading = false;
 
         for index in self.worktree_indices.values_mut() {
@@ -206,7 +276,10 @@ impl ProjectIndex {
                     break;
                 }
                 WorktreeIndexHandle::Loaded { index, .. } => {
-                    indexing_count += index.read(cx).entry_ids_being_indexed().len();
+                    let entries = index.read(cx).entry_ids_being_indexed();
+                    indexing_count += entries.len();
+                    queued_count += entries.queued_count();
+                    embedded_chunk_count += entries.embedded_chunk_count();
                 }
             }
         }
@@ -214,7 +287,20 @@ impl ProjectIndex {
         let status = if any_loading {
             Status::Loading
         } else if let Some(remaining_count) = NonZeroUsize::new(indexing_count) {
-            Status::Scanning { remaining_count }
+            // The counts tracked by each worktree are cumulative, so report them relative to
+            // when this scan started.
+            if !matches!(self.last_status, Status::Scanning { .. }) {
+                self.scan_start_queued_count = queued_count.saturating_sub(indexing_count);
+                self.scan_start_embedded_chunk_count = embedded_chunk_count;
+            }
+            Status::Scanning {
+                remaining_count,
+                total_count: queued_count
+                    .saturating_sub(self.scan_start_queued_count)
+                    .max(indexing_count),
+                embedded_chunk_count: embedded_chunk_count
+                    .saturating_sub(self.scan_start_embedded_chunk_count),
+            }
         } else {
             Status::Idle
         };
@@ -231,11 +317,101 @@ impl ProjectIndex {
         limit: usize,
         cx: &App,
     ) -> Task<Result<Vec<SearchResult>>> {
+        self.search_with_options(
+            queries,
+            SearchOptions {
+                limit,
+                ..Default::default()
+            },
+            cx,
+        )
+    }
+
+    /// Like [`Self::search`], but skips the `offset` best results first, so that results
+    /// `offset..offset + limit` can be fetched page by page. Results with equal scores are
+    /// ordered by worktree, path and range, which keeps consecutive pages disjoint.
+    pub fn search_page(
+        &self,
+        queries: Vec<String>,
+        offset: usize,
+        limit: usize,
+        cx: &App,
+    ) -> Task<Result<Vec<SearchResult>>> {
+        self.search_with_options(
+            queries,
+            SearchOptions {
+                offset,
+                limit,
+                ..Default::default()
+            },
+            cx,
+        )
+    }
+
+    /// Like [`Self::search`], but only returns chunks of the file at the given path.
+    pub fn search_path(
+        &self,
+        queries: Vec<String>,
+        path: ProjectPath,
+        limit: usize,
+        cx: &App,
+    ) -> Task<Result<Vec<SearchResult>>> {
+        self.search_with_options(
+            queries,
+            SearchOptions {
+                path: Some(path),
+                limit,
+                ..Default::default()
+            },
+            cx,
+        )
+    }
+
+    /// Searches for chunks similar to `code`, such as a selection at `source_range` in the file
+    /// at `source`. The code is embedded like the indexed chunks of `source` rather than like a
+    /// query, and chunks of `source` that overlap `source_range` are skipped so that the code
+    /// doesn't find itself.
+    pub fn search_similar(
+        &self,
+        code: String,
+        source: ProjectPath,
+        source_range: Range<usize>,
+        limit: usize,
+        cx: &App,
+    ) -> Task<Result<Vec<SearchResult>>> {
+        self.search_with_options(
+            vec![code],
+            SearchOptions {
+                excluded: Some((source, source_range)),
+                limit,
+                queries_are_code: true,
+                ..Default::default()
+            },
+            cx,
+        )
+    }
+
+    /// Searches for chunks matching any of the `queries`, as configured by `options`.
+    pub fn search_with_options(
+        &self,
+        queries: Vec<String>,
+        options: SearchOptions,
+        cx: &App,
+    ) -> Task<Result<Vec<SearchResult>>> {
+        let SearchOptions {
+            path,
+            excluded,
+            offset,
+            limit,
+            queries_are_code,
+            dedup_results,
+        } = options;
         let (chunks_tx, chunks_rx) = channel::bounded(1024);
         let mut worktree_scan_tasks = Vec::new();
         for worktree_index in self.worktree_indices.values() {
             let worktree_index = worktree_index.clone();
             let chunks_tx = chunks_tx.clone();
+            let path = path.clone();
             worktree_scan_tasks.push(cx.spawn(async move |cx| {
                 let index = match worktree_index {
                     WorktreeIndexHandle::Loading { index } => {
@@ -253,6 +429,21 @@ impl ProjectIndex {
                             let txn = db_connection
                                 .read_txn()
                                 .context("failed to create read transaction")?;
+                            if let Some(path) = path {
+                                if path.worktree_id != worktree_id {
+                                    return anyhow::Ok(());
+                                }
+                                let key = db_key_for_path(&path.path);
+                                let db_embedded_file =
+                                    db.get(&txn, &key).context("failed to read database")?;
+                                for chunk in db_embedded_file.into_iter().flat_map(|f| f.chunks) {
+                                    chunks_tx
+                                        .send((worktree_id, path.path.clone(), chunk))
+                                        .await?;
+                                }
+                                return anyhow::Ok(());
+                            }
+
                             let db_entries = db.iter(&txn).context("failed to iterate database")?;
                             for db_entry in db_entries {
                                 let (_key, db_embedded_file) = db_entry?;
@@ -270,18 +461,47 @@ impl ProjectIndex {
         }
         drop(chunks_tx);
 
+        // Code is embedded the same way as the chunks of the file it comes from.
+        let code_texts = queries_are_code.then(|| {
+            let code_path = excluded
+                .as_ref()
+                .map(|(path, _)| path)
+                .or(path.as_ref())
+                .map(|path| &*path.path);
+            queries
+                .iter()
+                .map(|code| {
+                    self.chunking_options
+                        .embedded_text(code, code_path)
+                        .into_owned()
+                })
+                .collect::<Vec<_>>()
+        });
         let project = self.project.clone();
         let embedding_provider = self.embedding_provider.clone();
+        let embed_timeout = self.embed_timeout;
         cx.spawn(async move |cx| {
             #[cfg(debug_assertions)]
             let embedding_query_start = std::time::Instant::now();
             log::info!("Searching for {queries:?}");
-            let queries: Vec<TextToEmbed> = queries
-                .iter()
-                .map(|s| TextToEmbed::new(s.as_str()))
-                .collect();
-
-            let query_embeddings = embedding_provider.embed(&queries[..]).await?;
+            let queries: Vec<TextToEmbed> = match &code_texts {
+                Some(code_texts) => code_texts
+                    .iter()
+                    .map(|text| TextToEmbed::new(text))
+                    .collect(),
+                None => queries
+                    .iter()
+                    .map(|query| TextToEmbed::new_query(query))
+                    .collect(),
+            };
+
+            let query_embeddings = embed_with_timeout(
+                embedding_provider.as_ref(),
+                &queries,
+                embed_timeout,
+                cx.background_executor(),
+            )
+            .await?;
             anyhow::ensure!(
                 query_embeddings.len() == queries.len(),
                 "The number of query embeddings does not match the number of queries"
@@ -292,6 +512,7 @@ impl ProjectIndex {
                 results_by_worker.push(Vec::<WorktreeSearchResult>::new());
             }
 
+            let keep = offset.saturating_add(limit);
             #[cfg(debug_assertions)]
             let search_start = std::time::Instant::now();
             cx.background_executor()
@@ -299,26 +520,45 @@ impl ProjectIndex {
                     for results in results_by_worker.iter_mut() {
                         cx.spawn(async {
                             while let Ok((worktree_id, path, chunk)) = chunks_rx.recv().await {
+                                if let Some((excluded_path, excluded_range)) = &excluded
+                                    && excluded_path.worktree_id == worktree_id
+                                    && excluded_path.path == path
+                                    && chunk.chunk.range.start < excluded_range.end
+                                    && excluded_range.start < chunk.chunk.range.end
+                                {
+                                    continue;
+                                }
                                 let (score, query_index) =
                                     chunk.embedding.similarity(&query_embeddings);
 
-                                let ix = match results.binary_search_by(|probe| {
-                                    score.partial_cmp(&probe.score).unwrap_or(Ordering::Equal)
-                                }) {
+                                let result = WorktreeSearchResult {
+                                    worktree_id,
+                                    path,
+                                    range: chunk.chunk.range.clone(),
+                                    query_index,
+                                    score,
+                                    digest: chunk.chunk.digest,
+                                };
+                                if dedup_results
+                                    && let Some(duplicate_ix) = results
+                                        .iter()
+                                        .position(|probe| probe.digest == result.digest)
+                                {
+                                    if compare_results(&results[duplicate_ix], &result)
+                                        != Ordering::Greater
+                                    {
+                                        continue;
+                                    }
+                                    results.remove(duplicate_ix);
+                                }
+                                let ix = match results
+                                    .binary_search_by(|probe| compare_results(probe, &result))
+                                {
                                     Ok(ix) | Err(ix) => ix,
                                 };
-                                if ix < limit {
-                                    results.insert(
-                                        ix,
-                                        WorktreeSearchResult {
-                                            worktree_id,
-                                            path: path.clone(),
-                                            range: chunk.chunk.range.clone(),
-                                            query_index,
-                                            score,
-                                        },
-                                    );
-                                    if results.len() > limit {
+                                if ix < keep {
+                                    results.insert(ix, result);
+                                    if results.len() > keep {
                                         results.pop();
                                     }
                                 }
@@ -333,9 +573,18 @@ impl ProjectIndex {
             }
 
             project.read_with(cx, |project, cx| {
-                let mut search_results = Vec::with_capacity(results_by_worker.len() * limit);
-                for worker_results in results_by_worker {
-                    search_results.extend(worker_results.into_iter().filter_map(|result| {
+                let mut worktree_results =
+                    results_by_worker.into_iter().flatten().collect::<Vec<_>>();
+                worktree_results.sort_unstable_by(compare_results);
+                if dedup_results {
+                    let mut seen_digests = HashSet::default();
+                    worktree_results.retain(|result| seen_digests.insert(result.digest));
+                }
+                let search_results = worktree_results
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit)
+                    .filter_map(|result| {
                         Some(SearchResult {
                             worktree: project.worktree_for_id(result.worktree_id, cx)?,
                             path: result.path,
@@ -343,12 +592,8 @@ impl ProjectIndex {
                             score: result.score,
                             query_index: result.query_index,
                         })
-                    }));
-                }
-                search_results.sort_unstable_by(|a, b| {
-                    b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)
-                });
-                search_results.truncate(limit);
+                    })
+                    .collect::<Vec<_>>();
 
                 #[cfg(debug_assertions)]
                 {
@@ -546,3 +791,24 @@ impl ProjectIndex {
 }
 
 impl EventEmitter<Status> for ProjectIndex {}
+
+/// Orders results by descending score, breaking ties by location so that the order is total
+/// and pages of results stay stable.
+fn compare_results(a: &WorktreeSearchResult, b: &WorktreeSearchResult) -> Ordering {
+    compare_by_score(
+        (a.score, (a.worktree_id, &a.path, a.range.start)),
+        (b.score, (b.worktree_id, &b.path, b.range.start)),
+    )
+}
+
+/// Orders results from the highest score to the lowest, breaking ties by the given keys so that
+/// the order is deterministic.
+pub(crate) fn compare_by_score<K: Ord>(
+    (a_score, a_key): (f32, K),
+    (b_score, b_key): (f32, K),
+) -> Ordering {
+    b_score
+        .partial_cmp(&a_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| a_key.cmp(&b_key))
+}
@@ -11,24 +11,35 @@ mod worktree_index;
 use anyhow::{Context as _, Result};
 use collections::HashMap;
 use fs::Fs;
-use gpui::{App, AppContext as _, AsyncApp, BorrowAppContext, Context, Entity, Global, WeakEntity};
+use gpui::{
+    App, AppContext as _, AsyncApp, BorrowAppContext, Context, Entity, Global, Task, WeakEntity,
+};
+use heed::types::{DecodeIgnore, Str, Unit};
 use language::LineEnding;
 use project::{Project, Worktree};
 use std::{
     cmp::Ordering,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use util::ResultExt as _;
 use workspace::Workspace;
+use worktree_index::{
+    WORKTREE_REGISTRY_DB_NAME, embedding_db_name, embedding_metadata_db_name, file_digest_db_name,
+    summary_db_name,
+};
 
+pub use chunking::ChunkingOptions;
 pub use embedding::*;
-pub use project_index::{LoadedSearchResult, ProjectIndex, SearchResult, Status};
+pub use project_index::{LoadedSearchResult, ProjectIndex, SearchOptions, SearchResult, Status};
 pub use project_index_debug_view::ProjectIndexDebugView;
 pub use summary_index::FileSummary;
 
 pub struct SemanticDb {
     embedding_provider: Arc<dyn EmbeddingProvider>,
+    chunking_options: ChunkingOptions,
+    embed_timeout: Duration,
     db_connection: Option<heed::Env>,
     project_indices: HashMap<WeakEntity<Project>, Entity<ProjectIndex>>,
 }
@@ -54,6 +65,24 @@ impl SemanticDb {
             .await
             .context("opening database connection")?;
 
+        // Every worktree shares the same cache, so that its size is tracked in one place.
+        let embedding_provider = cx
+            .background_spawn({
+                let db_connection = db_connection.clone();
+                async move {
+                    let mut txn = db_connection.write_txn()?;
+                    let provider = CachingEmbeddingProvider::new(
+                        embedding_provider,
+                        db_connection.clone(),
+                        &mut txn,
+                    )?;
+                    txn.commit()?;
+                    anyhow::Ok(Arc::new(provider) as Arc<dyn EmbeddingProvider>)
+                }
+            })
+            .await
+            .context("opening embedding cache")?;
+
         cx.update(|cx| {
             cx.observe_new(
                 |workspace: &mut Workspace, _window, cx: &mut Context<Workspace>| {
@@ -75,12 +104,38 @@ impl SemanticDb {
         Ok(SemanticDb {
             db_connection: Some(db_connection),
             embedding_provider,
+            chunking_options: ChunkingOptions::default(),
+            embed_timeout: DEFAULT_EMBED_TIMEOUT,
             project_indices: HashMap::default(),
         })
     }
 
+    /// Sets the options used to chunk files in project indices created after this call.
+    pub fn with_chunking_options(mut self, chunking_options: ChunkingOptions) -> Self {
+        self.chunking_options = chunking_options;
+        self
+    }
+
+    /// Sets how long project indices created after this call wait for the embedding provider,
+    /// both while indexing and while searching, before treating a request as failed.
+    pub fn with_embed_timeout(mut self, embed_timeout: Duration) -> Self {
+        self.embed_timeout = embed_timeout;
+        self
+    }
+
     pub async fn load_results(
+        results: Vec<SearchResult>,
+        fs: &Arc<dyn Fs>,
+        cx: &AsyncApp,
+    ) -> Result<Vec<LoadedSearchResult>> {
+        Self::load_results_with_context(results, 0, fs, cx).await
+    }
+
+    /// Like [`Self::load_results`], but grows each excerpt by up to `context_lines` lines before
+    /// and after the matched chunk. Excerpts that come to overlap are merged.
+    pub async fn load_results_with_context(
         mut results: Vec<SearchResult>,
+        context_lines: u32,
         fs: &Arc<dyn Fs>,
         cx: &AsyncApp,
     ) -> Result<Vec<LoadedSearchResult>> {
@@ -155,9 +210,9 @@ impl SemanticDb {
                 range_end += 1;
             }
 
-            let start_row = file_content[0..range_start].matches('\n').count() as u32;
+            let mut start_row = file_content[0..range_start].matches('\n').count() as u32;
             let mut end_row = file_content[0..range_end].matches('\n').count() as u32;
-            let start_line_byte_offset = file_content[0..range_start]
+            let mut start_line_byte_offset = file_content[0..range_start]
                 .rfind('\n')
                 .map(|pos| pos + 1)
                 .unwrap_or_default();
@@ -170,15 +225,43 @@ impl SemanticDb {
                     .map(|pos| range_end + pos + 1)
                     .unwrap_or_else(|| file_content.len());
             }
+            for _ in 0..context_lines {
+                if start_line_byte_offset == 0 {
+                    break;
+                }
+                start_line_byte_offset = file_content[..start_line_byte_offset - 1]
+                    .rfind('\n')
+                    .map(|pos| pos + 1)
+                    .unwrap_or_default();
+                start_row -= 1;
+            }
+            for _ in 0..context_lines {
+                if end_line_byte_offset >= file_content.len() {
+                    break;
+                }
+                end_line_byte_offset = file_content[end_line_byte_offset..]
+                    .find('\n')
+                    .map(|pos| end_line_byte_offset + pos + 1)
+                    .unwrap_or_else(|| file_content.len());
+                end_row += 1;
+            }
             let mut excerpt_content =
                 file_content[start_line_byte_offset..end_line_byte_offset].to_string();
             LineEnding::normalize(&mut excerpt_content);
 
             if let Some(prev_result) = loaded_results.last_mut() {
                 if prev_result.full_path == full_path {
-                    if *prev_result.row_range.end() + 1 == start_row {
-                        prev_result.row_range = *prev_result.row_range.start()..=end_row;
-                        prev_result.excerpt_content.push_str(&excerpt_content);
+                    let prev_end_row = *prev_result.row_range.end();
+                    // Chunks can share rows, either because they were split with an overlap or
+                    // in the middle of a line, so only append the rows that aren't present yet.
+                    if start_row <= prev_end_row + 1 {
+                        if end_row > prev_end_row {
+                            let shared_row_count = (prev_end_row + 1 - start_row) as usize;
+                            prev_result.row_range = *prev_result.row_range.start()..=end_row;
+                            prev_result.excerpt_content.extend(
+                                excerpt_content.split_inclusive('\n').skip(shared_row_count),
+                            );
+                        }
                         continue;
                     }
                 }
@@ -224,6 +307,122 @@ impl SemanticDb {
         })
     }
 
+    /// Returns the absolute paths of the worktrees that have a non-empty embedding index.
+    pub fn indexed_worktree_paths(&self, cx: &App) -> Task<Result<Vec<PathBuf>>> {
+        let db_connection = self.db_connection.clone();
+        cx.background_spawn(async move {
+            let db_connection = db_connection.context("database connection is closed")?;
+            let txn = db_connection
+                .read_txn()
+                .context("failed to create read transaction")?;
+            let Some(registry_db) =
+                db_connection.open_database::<Str, Unit>(&txn, Some(WORKTREE_REGISTRY_DB_NAME))?
+            else {
+                return Ok(Vec::new());
+            };
+
+            let mut indexed_worktree_paths = Vec::new();
+            for entry in registry_db.iter(&txn)? {
+                let (path, ()) = entry?;
+                let path = PathBuf::from(path);
+                let db_name = embedding_db_name(&path);
+                let Some(db) =
+                    db_connection.open_database::<Str, DecodeIgnore>(&txn, Some(&db_name))?
+                else {
+                    continue;
+                };
+                if !db.is_empty(&txn)? {
+                    indexed_worktree_paths.push(path);
+                }
+            }
+            Ok(indexed_worktree_paths)
+        })
+    }
+
+    /// Clears everything stored for the worktree at the given path, so that it will be indexed
+    /// from scratch. Does nothing if the worktree was never indexed.
+    ///
+    /// The embedding cache is shared by all worktrees, so it's kept. Use
+    /// [`Self::clear_embedding_cache`] to recompute every embedding.
+    ///
+    /// Project indices that have the worktree open reload it once it's cleared, so that it's
+    /// rescanned right away.
+    pub fn delete_worktree_index(
+        &self,
+        worktree_abs_path: &Path,
+        cx: &mut App,
+    ) -> Task<Result<()>> {
+        let db_connection = self.db_connection.clone();
+        let worktree_abs_path: Arc<Path> = worktree_abs_path.into();
+        let db_names = [
+            embedding_db_name(&worktree_abs_path),
+            embedding_metadata_db_name(&worktree_abs_path),
+            file_digest_db_name(&worktree_abs_path),
+            summary_db_name(&worktree_abs_path),
+        ];
+        let clear_dbs = cx.background_spawn({
+            let worktree_abs_path = worktree_abs_path.clone();
+            async move {
+                let db_connection = db_connection.context("database connection is closed")?;
+                let mut txn = db_connection.write_txn()?;
+                for db_name in &db_names {
+                    if let Some(db) = db_connection
+                        .open_database::<DecodeIgnore, DecodeIgnore>(&txn, Some(db_name))?
+                    {
+                        log::info!("clearing database {db_name:?}");
+                        db.clear(&mut txn)?;
+                    }
+                }
+                if let Some(registry_db) = db_connection
+                    .open_database::<Str, Unit>(&txn, Some(WORKTREE_REGISTRY_DB_NAME))?
+                {
+                    registry_db.delete(&mut txn, &worktree_abs_path.to_string_lossy())?;
+                }
+                txn.commit()?;
+                anyhow::Ok(())
+            }
+        });
+        let project_indices = self.project_indices.values().cloned().collect::<Vec<_>>();
+        cx.spawn(async move |cx| {
+            clear_dbs.await?;
+            for project_index in project_indices {
+                project_index.update(cx, |project_index, cx| {
+                    project_index.reload_worktree_index(&worktree_abs_path, cx)
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Clears the cached embeddings of every model, including caches of models that are no
+    /// longer used, so that all embeddings are requested from the provider again.
+    pub fn clear_embedding_cache(&self, cx: &App) -> Task<Result<()>> {
+        let db_connection = self.db_connection.clone();
+        cx.background_spawn(async move {
+            let db_connection = db_connection.context("database connection is closed")?;
+            let mut txn = db_connection.write_txn()?;
+            let mut cache_db_names = Vec::new();
+            if let Some(db_names) = db_connection.open_database::<Str, DecodeIgnore>(&txn, None)? {
+                for entry in db_names.iter(&txn)? {
+                    let (db_name, ()) = entry?;
+                    if db_name.starts_with(EMBEDDING_CACHE_DB_PREFIX) {
+                        cache_db_names.push(db_name.to_string());
+                    }
+                }
+            }
+            for db_name in &cache_db_names {
+                if let Some(db) = db_connection
+                    .open_database::<DecodeIgnore, DecodeIgnore>(&txn, Some(db_name))?
+                {
+                    log::info!("clearing database {db_name:?}");
+                    db.clear(&mut txn)?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+    }
+
     pub fn create_project_index(
         &mut self,
         project: Entity<Project>,
@@ -234,6 +433,8 @@ impl SemanticDb {
                 project.clone(),
                 self.db_connection.clone().unwrap(),
                 self.embedding_provider.clone(),
+                self.chunking_options.clone(),
+                self.embed_timeout,
                 cx,
             )
         });
@@ -253,6 +454,47 @@ impl SemanticDb {
 
         project_index
     }
+
+    /// Searches each of the given projects that has an index, and merges their results by
+    /// score. Each result is paired with the project it came from. Projects that fail to search
+    /// are logged and skipped.
+    pub fn search_projects(
+        &self,
+        projects: &[Entity<Project>],
+        queries: Vec<String>,
+        limit: usize,
+        cx: &App,
+    ) -> Task<Result<Vec<(Entity<Project>, SearchResult)>>> {
+        let searches = projects
+            .iter()
+            .filter_map(|project| {
+                let project_index = self.project_indices.get(&project.downgrade())?;
+                let search = project_index.read(cx).search(queries.clone(), limit, cx);
+                Some((project.clone(), search))
+            })
+            .collect::<Vec<_>>();
+        cx.spawn(async move |_| {
+            let mut results = Vec::new();
+            for (project, search) in searches {
+                // A project that fails to search shouldn't hide the results of the others.
+                if let Some(project_results) = search.await.log_err() {
+                    results.extend(
+                        project_results
+                            .into_iter()
+                            .map(|result| (project.clone(), result)),
+                    );
+                }
+            }
+            let sort_key = |(project, result): &(Entity<Project>, SearchResult)| {
+                let location = (result.path.clone(), result.range.start);
+                let key = (project.entity_id(), result.worktree.entity_id(), location);
+                (result.score, key)
+            };
+            results.sort_by(|a, b| project_index::compare_by_score(sort_key(a), sort_key(b)));
+            results.truncate(limit);
+            Ok(results)
+        })
+    }
 }
 
 impl Drop for SemanticDb {
@@ -265,19 +507,20 @@ impl Drop for SemanticDb {
 mod tests {
     use super::*;
     use chunking::Chunk;
-    use embedding_index::{ChunkedFile, EmbeddingIndex};
+    use embedding_index::{ChunkedFile, EmbeddingIndex, MAX_INDEXED_FILE_SIZE};
     use feature_flags::FeatureFlagAppExt;
     use fs::FakeFs;
     use futures::{FutureExt, future::BoxFuture};
     use gpui::TestAppContext;
     use indexing::IndexingEntrySet;
     use language::language_settings::AllLanguageSettings;
-    use project::{Project, ProjectEntryId};
+    use parking_lot::Mutex;
+    use project::{Project, ProjectEntryId, ProjectPath};
     use serde_json::json;
     use settings::SettingsStore;
     use smol::channel;
     use std::{future, path::Path, sync::Arc};
-    use util::path;
+    use util::{path, paths::PathMatcher};
 
     fn init_test(cx: &mut TestAppContext) {
         zlog::init_test();
@@ -296,19 +539,29 @@ mod tests {
 
     pub struct TestEmbeddingProvider {
         batch_size: usize,
+        dimension: usize,
+        max_input_tokens: Option<usize>,
         compute_embedding: Box<dyn Fn(&str) -> Result<Embedding> + Send + Sync>,
     }
 
     impl TestEmbeddingProvider {
         pub fn new(
             batch_size: usize,
+            dimension: usize,
             compute_embedding: impl 'static + Fn(&str) -> Result<Embedding> + Send + Sync,
         ) -> Self {
             Self {
                 batch_size,
+                dimension,
+                max_input_tokens: None,
                 compute_embedding: Box::new(compute_embedding),
             }
         }
+
+        pub fn with_max_input_tokens(mut self, max_input_tokens: usize) -> Self {
+            self.max_input_tokens = Some(max_input_tokens);
+            self
+        }
     }
 
     impl EmbeddingProvider for TestEmbeddingProvider {
@@ -326,6 +579,30 @@ mod tests {
         fn batch_size(&self) -> usize {
             self.batch_size
         }
+
+        fn dimension(&self) -> usize {
+            self.dimension
+        }
+
+        fn max_input_tokens(&self) -> Option<usize> {
+            self.max_input_tokens
+        }
+
+        fn model_id(&self) -> &str {
+            "test"
+        }
+    }
+
+    /// Creates a project whose only worktree, at `/fake_project`, contains the given tree.
+    async fn test_project(
+        tree: serde_json::Value,
+        cx: &mut TestAppContext,
+    ) -> (Arc<FakeFs>, Entity<Project>) {
+        let fs = FakeFs::new(cx.executor());
+        let project_path = Path::new(path!("/fake_project"));
+        fs.insert_tree(project_path, tree).await;
+        let project = Project::test(fs.clone(), [project_path], cx).await;
+        (fs, project)
     }
 
     #[gpui::test]
@@ -343,7 +620,7 @@ mod tests {
 
         let mut semantic_index = SemanticDb::new(
             temp_dir.path().into(),
-            Arc::new(TestEmbeddingProvider::new(16, |text| {
+            Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
                 let mut embedding = vec![0f32; 2];
                 // if the text contains garbage, give it a 1 in the first dimension
                 if text.contains("garbage in") {
@@ -438,132 +715,1194 @@ mod tests {
         let content = content[range.clone()].to_owned();
 
         assert!(content.contains("garbage in, garbage out"));
+
+        // Searching within a file never returns chunks of other files.
+        let main_rs_path = ProjectPath {
+            worktree_id: cx.update(|cx| search_result.worktree.read(cx).id()),
+            path: Path::new(path!("fixture/main.rs")).into(),
+        };
+        let results = cx
+            .update(|cx| {
+                let project_index = project_index.read(cx);
+                let query = "garbage in, garbage out";
+                project_index.search_path(vec![query.into()], main_rs_path.clone(), 4, cx)
+            })
+            .await
+            .unwrap();
+        assert!(!results.is_empty());
+        assert!(
+            results
+                .iter()
+                .all(|result| result.path == main_rs_path.path),
+            "found results outside of main.rs: {:?}",
+            results
+        );
+
+        // Consecutive pages are disjoint and continue the order of the first page.
+        let (first_page, second_page, all_results) = cx.update(|cx| {
+            let project_index = project_index.read(cx);
+            let query = "garbage in, garbage out";
+            (
+                project_index.search_page(vec![query.into()], 0, 1, cx),
+                project_index.search_page(vec![query.into()], 1, 1, cx),
+                project_index.search(vec![query.into()], 2, cx),
+            )
+        });
+        let first_page = first_page.await.unwrap();
+        let second_page = second_page.await.unwrap();
+        let all_results = all_results.await.unwrap();
+        assert_eq!(first_page.len(), 1);
+        assert_eq!(second_page.len(), 1);
+        let locations = |results: &[SearchResult]| {
+            results
+                .iter()
+                .map(|result| (result.path.clone(), result.range.clone()))
+                .collect::<Vec<_>>()
+        };
+        let paged_locations = [locations(&first_page), locations(&second_page)].concat();
+        assert_eq!(paged_locations, locations(&all_results));
+        assert!(
+            first_page[0].score >= second_page[0].score,
+            "second page should not score higher than the first"
+        );
     }
 
     #[gpui::test]
-    async fn test_embed_files(cx: &mut TestAppContext) {
+    async fn test_list_and_delete_worktree_indices(cx: &mut TestAppContext) {
         cx.executor().allow_parking();
 
-        let provider = Arc::new(TestEmbeddingProvider::new(3, |text| {
-            anyhow::ensure!(
-                !text.contains('g'),
-                "cannot embed text containing a 'g' character"
-            );
-            Ok(Embedding::new(
-                ('a'..='z')
-                    .map(|char| text.chars().filter(|c| *c == char).count() as f32)
-                    .collect(),
-            ))
-        }));
+        init_test(cx);
 
-        let (indexing_progress_tx, _) = channel::unbounded();
-        let indexing_entries = Arc::new(IndexingEntrySet::new(indexing_progress_tx));
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
 
-        let (chunked_files_tx, chunked_files_rx) = channel::unbounded::<ChunkedFile>();
-        chunked_files_tx
-            .send_blocking(ChunkedFile {
-                path: Path::new("test1.md").into(),
-                mtime: None,
-                handle: indexing_entries.insert(ProjectEntryId::from_proto(0)),
-                text: "abcdefghijklmnop".to_string(),
-                chunks: [0..4, 4..8, 8..12, 12..16]
-                    .into_iter()
-                    .map(|range| Chunk {
-                        range,
-                        digest: Default::default(),
-                    })
-                    .collect(),
-            })
-            .unwrap();
-        chunked_files_tx
-            .send_blocking(ChunkedFile {
-                path: Path::new("test2.md").into(),
-                mtime: None,
-                handle: indexing_entries.insert(ProjectEntryId::from_proto(1)),
-                text: "qrstuvwxyz".to_string(),
-                chunks: [0..4, 4..8, 8..10]
-                    .into_iter()
-                    .map(|range| Chunk {
-                        range,
-                        digest: Default::default(),
-                    })
-                    .collect(),
-            })
-            .unwrap();
-        chunked_files_tx.close();
+        let temp_dir = tempfile::tempdir().unwrap();
 
-        let embed_files_task =
-            cx.update(|cx| EmbeddingIndex::embed_files(provider.clone(), chunked_files_rx, cx));
-        embed_files_task.task.await.unwrap();
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
 
-        let embedded_files_rx = embed_files_task.files;
-        let mut embedded_files = Vec::new();
-        while let Ok((embedded_file, _)) = embedded_files_rx.recv().await {
-            embedded_files.push(embedded_file);
+        let project_path = Path::new(path!("/fake_project"));
+        let (_, project) = test_project(json!({ "main.rs": "fn main() {}" }), cx).await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+        while cx
+            .update(|cx| semantic_index.remaining_summaries(&project.downgrade(), cx))
+            .unwrap()
+            > 0
+        {
+            cx.run_until_parked();
         }
 
-        assert_eq!(embedded_files.len(), 1);
-        assert_eq!(embedded_files[0].path.as_ref(), Path::new("test2.md"));
-        assert_eq!(
-            embedded_files[0]
-                .chunks
+        let indexed_worktree_paths = cx
+            .update(|cx| semantic_index.indexed_worktree_paths(cx))
+            .await
+            .unwrap();
+        assert_eq!(indexed_worktree_paths, vec![project_path.to_path_buf()]);
+
+        // Deleting the index of a worktree that's open rescans the worktree.
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        cx.update(|cx| {
+            let statuses = statuses.clone();
+            cx.subscribe(&project_index, move |_, status: &Status, _| {
+                statuses.lock().push(*status);
+            })
+            .detach();
+        });
+        cx.update(|cx| semantic_index.delete_worktree_index(project_path, cx))
+            .await
+            .unwrap();
+        cx.run_until_parked();
+        assert!(
+            statuses
+                .lock()
                 .iter()
-                .map(|embedded_chunk| { embedded_chunk.embedding.clone() })
-                .collect::<Vec<Embedding>>(),
-            vec![
-                (provider.compute_embedding)("qrst").unwrap(),
-                (provider.compute_embedding)("uvwx").unwrap(),
-                (provider.compute_embedding)("yz").unwrap(),
-            ],
+                .any(|status| matches!(status, Status::Scanning { .. }))
+        );
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            1
         );
+        let indexed_worktree_paths = cx
+            .update(|cx| semantic_index.indexed_worktree_paths(cx))
+            .await
+            .unwrap();
+        assert_eq!(indexed_worktree_paths, vec![project_path.to_path_buf()]);
+
+        // Once no project has the worktree open, deleting its index leaves nothing to list.
+        semantic_index.project_indices.clear();
+        drop(project_index);
+        cx.run_until_parked();
+        cx.update(|cx| semantic_index.delete_worktree_index(project_path, cx))
+            .await
+            .unwrap();
+        let indexed_worktree_paths = cx
+            .update(|cx| semantic_index.indexed_worktree_paths(cx))
+            .await
+            .unwrap();
+        assert!(indexed_worktree_paths.is_empty());
+
+        // Deleting is idempotent, including for worktrees that were never indexed.
+        cx.update(|cx| semantic_index.delete_worktree_index(project_path, cx))
+            .await
+            .unwrap();
+        cx.update(|cx| semantic_index.delete_worktree_index(Path::new("/never_indexed"), cx))
+            .await
+            .unwrap();
     }
 
     #[gpui::test]
-    async fn test_load_search_results(cx: &mut TestAppContext) {
+    async fn test_index_skips_ignored_files(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
         init_test(cx);
 
-        let fs = FakeFs::new(cx.executor());
-        let project_path = Path::new("/fake_project");
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
 
-        let file1_content = "one\ntwo\nthree\nfour\nfive\n";
-        let file2_content = "aaa\nbbb\nccc\nddd\neee\n";
+        let temp_dir = tempfile::tempdir().unwrap();
 
-        fs.insert_tree(
-            project_path,
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let project_path = Path::new(path!("/fake_project"));
+        let (fs, project) = test_project(
             json!({
-                "file1.txt": file1_content,
-                "file2.txt": file2_content,
+                ".git": {},
+                ".gitignore": "*.generated.rs\n",
+                "src": {
+                    "main.rs": "fn main() {}",
+                    "old.generated.rs": "fn old() {}",
+                },
             }),
+            cx,
         )
         .await;
 
-        let fs = fs as Arc<dyn Fs>;
-        let project = Project::test(fs.clone(), [project_path], cx).await;
-        let worktree = project.read_with(cx, |project, cx| project.worktrees(cx).next().unwrap());
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+        // Only .gitignore and main.rs are indexed.
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            2
+        );
 
-        // chunk that is already newline-aligned
-        let search_results = vec![SearchResult {
-            worktree: worktree.clone(),
-            path: Path::new("file1.txt").into(),
-            range: 0..file1_content.find("four").unwrap(),
-            score: 0.5,
-            query_index: 0,
-        }];
+        // Ignored files that are created later aren't indexed either.
+        fs.insert_file(
+            project_path.join("src/new.generated.rs"),
+            b"fn new() {}".to_vec(),
+        )
+        .await;
+        fs.insert_file(project_path.join("src/lib.rs"), b"fn lib() {}".to_vec())
+            .await;
+        cx.run_until_parked();
         assert_eq!(
-            SemanticDb::load_results(search_results, &fs, &cx.to_async())
-                .await
+            cx.update(|cx| project_index.read(cx).path_count(cx))
                 .unwrap(),
-            &[LoadedSearchResult {
-                path: Path::new("file1.txt").into(),
-                full_path: "fake_project/file1.txt".into(),
-                excerpt_content: "one\ntwo\nthree\n".into(),
-                row_range: 0..=2,
-                query_index: 0,
-            }]
+            3
         );
 
-        // chunk that is *not* newline-aligned
-        let search_results = vec![SearchResult {
+        // A file that becomes ignored after it was indexed has its embeddings deleted.
+        fs.insert_file(
+            project_path.join(".gitignore"),
+            b"*.generated.rs\nlib.rs\n".to_vec(),
+        )
+        .await;
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            2
+        );
+    }
+
+    #[gpui::test]
+    async fn test_index_skips_excluded_paths(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, {
+                let embedded_texts = embedded_texts.clone();
+                move |text| {
+                    embedded_texts.lock().push(text.to_string());
+                    Ok(Embedding::new(vec![1.0, 0.0]))
+                }
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap()
+        .with_chunking_options(ChunkingOptions {
+            exclude_globs: PathMatcher::new(["**/generated/**", "*.snap"]).unwrap(),
+            ..Default::default()
+        });
+
+        let project_path = Path::new(path!("/fake_project"));
+        let (fs, project) = test_project(
+            json!({
+                "src": {
+                    "main.rs": "fn main() {}",
+                    "generated": {
+                        "bindings.rs": "fn generated_binding() {}",
+                    },
+                },
+                "output.snap": "snapshot contents",
+            }),
+            cx,
+        )
+        .await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            1
+        );
+
+        // Excluded files that are created later are never chunked or embedded either.
+        fs.insert_file(
+            project_path.join("src/generated/more.rs"),
+            b"fn more_generated() {}".to_vec(),
+        )
+        .await;
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            1
+        );
+        let embedded_texts = embedded_texts.lock();
+        assert_eq!(*embedded_texts, ["fn main() {}"]);
+    }
+
+    #[gpui::test]
+    async fn test_index_skips_vendored_dirs(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let fs = FakeFs::new(cx.executor());
+        let project_path = Path::new(path!("/fake_project"));
+        fs.insert_tree(
+            project_path,
+            json!({
+                "node_modules": {
+                    "left-pad": { "index.js": "module.exports = leftPad;" },
+                },
+                "src": { "main.rs": "fn main() {}" },
+                "crates": {
+                    "foo": {
+                        "Cargo.toml": "[package]\nname = \"foo\"",
+                        "target": { "build.rs": "fn main() {}" },
+                    },
+                },
+            }),
+        )
+        .await;
+
+        for (index_vendored_dirs, expected_path_count) in [(false, 2), (true, 4)] {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let mut semantic_index = SemanticDb::new(
+                temp_dir.path().into(),
+                Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                    Ok(Embedding::new(vec![1.0, 0.0]))
+                })),
+                &mut cx.to_async(),
+            )
+            .await
+            .unwrap()
+            .with_chunking_options(ChunkingOptions {
+                index_vendored_dirs,
+                ..Default::default()
+            });
+
+            let project = Project::test(fs.clone(), [project_path], cx).await;
+            let project_index =
+                cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+            cx.run_until_parked();
+            assert_eq!(
+                cx.update(|cx| project_index.read(cx).path_count(cx))
+                    .unwrap(),
+                expected_path_count,
+                "index_vendored_dirs: {index_vendored_dirs}"
+            );
+        }
+    }
+
+    #[gpui::test]
+    async fn test_index_skips_large_files(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, {
+                let embedded_texts = embedded_texts.clone();
+                move |text| {
+                    embedded_texts.lock().push(text.to_string());
+                    Ok(Embedding::new(vec![1.0, 0.0]))
+                }
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        // Short lines, so that the file doesn't look minified.
+        let large_text = "x\n".repeat(MAX_INDEXED_FILE_SIZE as usize / 2 + 1);
+        let (_, project) = test_project(
+            json!({
+                "large.txt": large_text,
+                "main.rs": "fn main() {}",
+            }),
+            cx,
+        )
+        .await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        // The large file is recorded, so it isn't loaded again, but nothing of it is embedded.
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            2
+        );
+        let embedded_texts = embedded_texts.lock();
+        assert!(!embedded_texts.is_empty());
+        assert!(!embedded_texts.iter().any(|text| text.contains('x')));
+    }
+
+    #[gpui::test]
+    async fn test_index_respects_max_input_tokens(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let provider = TestEmbeddingProvider::new(16, 2, {
+            let embedded_texts = embedded_texts.clone();
+            move |text| {
+                embedded_texts.lock().push(text.to_string());
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            }
+        })
+        .with_max_input_tokens(64);
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(provider),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap()
+        .with_chunking_options(ChunkingOptions {
+            path_context: true,
+            ..Default::default()
+        });
+
+        let (_, project) =
+            test_project(json!({ "notes.txt": "some notes\n".repeat(200) }), cx).await;
+
+        let _project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        // Every chunk fits within the model's input, including the header embedded with it.
+        let embedded_texts = embedded_texts.lock();
+        assert!(embedded_texts.len() > 1);
+        assert!(
+            embedded_texts
+                .iter()
+                .all(|text| TextToEmbed::new(text).estimated_token_count() <= 64)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_search_dedup_results(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let (_, project) = test_project(
+            json!({
+                "needle.txt": "garbage in, garbage out",
+                "copy": {
+                    "needle.txt": "garbage in, garbage out",
+                },
+                "other.txt": "something else entirely",
+            }),
+            cx,
+        )
+        .await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        let search = |dedup_results, cx: &mut TestAppContext| {
+            cx.update(|cx| {
+                project_index.read(cx).search_with_options(
+                    vec!["garbage in, garbage out".into()],
+                    SearchOptions {
+                        limit: 10,
+                        dedup_results,
+                        ..Default::default()
+                    },
+                    cx,
+                )
+            })
+        };
+        let results = search(false, cx).await.unwrap();
+        assert_eq!(results.len(), 3);
+
+        // Both copies of the needle have the same content, so only the first is kept.
+        let results = search(true, cx).await.unwrap();
+        assert_eq!(results.len(), 2, "unexpected results: {results:?}");
+        let needle_count = results
+            .iter()
+            .filter(|result| result.path.ends_with("needle.txt"))
+            .count();
+        assert_eq!(needle_count, 1);
+    }
+
+    #[gpui::test]
+    async fn test_search_similar(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let code = "garbage in, garbage out";
+        let (_, project) = test_project(
+            json!({
+                "needle.txt": code,
+                "copy": {
+                    "needle.txt": code,
+                },
+            }),
+            cx,
+        )
+        .await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        let source = ProjectPath {
+            worktree_id: project.read_with(cx, |project, cx| {
+                project.worktrees(cx).next().unwrap().read(cx).id()
+            }),
+            path: Path::new("needle.txt").into(),
+        };
+        let results = cx
+            .update(|cx| {
+                project_index
+                    .read(cx)
+                    .search_similar(code.into(), source, 0..code.len(), 10, cx)
+            })
+            .await
+            .unwrap();
+        // The copy is found, but not the code itself.
+        assert_eq!(results.len(), 1, "unexpected results: {results:?}");
+        assert_eq!(
+            results[0].path.as_ref(),
+            Path::new(path!("copy/needle.txt"))
+        );
+    }
+
+    #[gpui::test]
+    async fn test_search_similar_with_embedded_text_options(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let embedded_texts = Arc::new(Mutex::new(Vec::new()));
+        let provider = TestEmbeddingProvider::new(16, 2, {
+            let embedded_texts = embedded_texts.clone();
+            move |text| {
+                embedded_texts.lock().push(text.to_string());
+                if text.ends_with("garbage in, garbage out") {
+                    Ok(Embedding::new(vec![1.0, 0.0]))
+                } else {
+                    Ok(Embedding::new(vec![0.0, 1.0]))
+                }
+            }
+        });
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(provider),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap()
+        .with_chunking_options(ChunkingOptions {
+            path_context: true,
+            normalize_whitespace: true,
+            ..Default::default()
+        });
+
+        let code = "garbage in,\n    garbage out\n";
+        let (_, project) = test_project(
+            json!({
+                "needle.txt": code,
+                "copy": {
+                    "needle.txt": code,
+                },
+                "haystack.txt": "hay",
+            }),
+            cx,
+        )
+        .await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        let source = ProjectPath {
+            worktree_id: project.read_with(cx, |project, cx| {
+                project.worktrees(cx).next().unwrap().read(cx).id()
+            }),
+            path: Path::new("needle.txt").into(),
+        };
+        let results = cx
+            .update(|cx| {
+                project_index
+                    .read(cx)
+                    .search_similar(code.into(), source, 0..code.len(), 1, cx)
+            })
+            .await
+            .unwrap();
+
+        // The code is embedded like a chunk of its own file, so it finds the copy.
+        let query_text = chunking::path_header(Path::new("needle.txt")) + "garbage in, garbage out";
+        assert!(embedded_texts.lock().contains(&query_text));
+        assert_eq!(results.len(), 1, "unexpected results: {results:?}");
+        assert_eq!(
+            results[0].path.as_ref(),
+            Path::new(path!("copy/needle.txt"))
+        );
+    }
+
+    #[gpui::test]
+    async fn test_search_projects(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/projects"),
+            json!({
+                "first": { "first.txt": "garbage in, garbage out" },
+                "second": { "second.txt": "garbage in, garbage out!" },
+                "unsearched": { "unsearched.txt": "garbage in, garbage out" },
+            }),
+        )
+        .await;
+        let mut projects = Vec::new();
+        for name in ["first", "second", "unsearched"] {
+            let project_path = Path::new(path!("/projects")).join(name);
+            let project = Project::test(fs.clone(), [project_path.as_path()], cx).await;
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+            projects.push(project);
+        }
+        cx.run_until_parked();
+
+        let results = cx
+            .update(|cx| {
+                semantic_index.search_projects(
+                    &projects[..2],
+                    vec!["garbage in, garbage out".into()],
+                    10,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        let labeled_paths = results
+            .iter()
+            .map(|(project, result)| {
+                let project_ix = projects.iter().position(|p| p == project).unwrap();
+                (project_ix, result.path.clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labeled_paths,
+            [
+                (0, Path::new("first.txt").into()),
+                (1, Path::new("second.txt").into())
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_search_projects_skips_failing_projects(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |text| {
+                Ok(Embedding::new(vec![text.len() as f32, 1.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/projects"),
+            json!({
+                "working": { "working.txt": "garbage in, garbage out" },
+                "broken": { "broken.txt": "garbage in, garbage out" },
+            }),
+        )
+        .await;
+        let working_project =
+            Project::test(fs.clone(), [Path::new(path!("/projects/working"))], cx).await;
+        cx.update(|cx| semantic_index.create_project_index(working_project.clone(), cx));
+
+        // The broken project's index can't embed anything, including the query.
+        let embedding_provider = semantic_index.embedding_provider.clone();
+        semantic_index.embedding_provider = Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+            Err(anyhow::anyhow!("failed to embed"))
+        }));
+        let broken_project =
+            Project::test(fs.clone(), [Path::new(path!("/projects/broken"))], cx).await;
+        cx.update(|cx| semantic_index.create_project_index(broken_project.clone(), cx));
+        semantic_index.embedding_provider = embedding_provider;
+        cx.run_until_parked();
+
+        let results = cx
+            .update(|cx| {
+                semantic_index.search_projects(
+                    &[broken_project, working_project.clone()],
+                    vec!["garbage in, garbage out".into()],
+                    10,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1, "unexpected results: {results:?}");
+        assert_eq!(results[0].0, working_project);
+        assert_eq!(results[0].1.path.as_ref(), Path::new("working.txt"));
+    }
+
+    #[gpui::test]
+    async fn test_search_projects_with_tied_scores(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/projects"),
+            json!({
+                "first": { "b.txt": "bbb", "a.txt": "aaa" },
+                "second": { "b.txt": "bbb", "a.txt": "aaa" },
+            }),
+        )
+        .await;
+        let mut projects = Vec::new();
+        for name in ["first", "second"] {
+            let project_path = Path::new(path!("/projects")).join(name);
+            let project = Project::test(fs.clone(), [project_path.as_path()], cx).await;
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+            projects.push(project);
+        }
+        cx.run_until_parked();
+        let mut sorted_projects = projects.clone();
+        sorted_projects.sort_by_key(|project| project.entity_id());
+
+        // Every result has the same score, so they're ordered by project, worktree and path,
+        // regardless of the order the projects are searched in.
+        for searched_projects in [projects.clone(), projects.iter().rev().cloned().collect()] {
+            let results = cx
+                .update(|cx| {
+                    semantic_index.search_projects(&searched_projects, vec!["query".into()], 10, cx)
+                })
+                .await
+                .unwrap();
+            let labeled_paths = results
+                .iter()
+                .map(|(project, result)| {
+                    let project_ix = sorted_projects.iter().position(|p| p == project).unwrap();
+                    (project_ix, result.path.clone())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                labeled_paths,
+                [
+                    (0, Path::new("a.txt").into()),
+                    (0, Path::new("b.txt").into()),
+                    (1, Path::new("a.txt").into()),
+                    (1, Path::new("b.txt").into()),
+                ]
+            );
+        }
+    }
+
+    #[gpui::test]
+    async fn test_index_many_files(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        // More files than are written in a single transaction.
+        let files = (0..150)
+            .map(|ix| (format!("file_{ix}.rs"), json!(format!("fn f{ix}() {{}}"))))
+            .collect::<serde_json::Map<_, _>>();
+        let (_, project) = test_project(serde_json::Value::Object(files), cx).await;
+
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).path_count(cx))
+                .unwrap(),
+            150
+        );
+    }
+
+    #[gpui::test]
+    async fn test_status_counts_each_scan(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(TestEmbeddingProvider::new(16, 2, |_| {
+                Ok(Embedding::new(vec![1.0, 0.0]))
+            })),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap();
+
+        let (fs, project) =
+            test_project(json!({ "a.rs": "fn a() {}", "b.rs": "fn b() {}" }), cx).await;
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        cx.update(|cx| {
+            let statuses = statuses.clone();
+            cx.subscribe(&project_index, move |_, status: &Status, _| {
+                statuses.lock().push(*status);
+            })
+            .detach();
+        });
+        let scanning_counts = |statuses: &[Status]| {
+            statuses
+                .iter()
+                .filter_map(|status| match status {
+                    Status::Scanning {
+                        total_count,
+                        embedded_chunk_count,
+                        ..
+                    } => Some((*total_count, *embedded_chunk_count)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).status()),
+            Status::Idle
+        );
+        let counts = scanning_counts(&statuses.lock());
+        assert_eq!(
+            counts.last().map(|(total, _)| *total),
+            Some(2),
+            "{counts:?}"
+        );
+        assert!(
+            counts.iter().all(|(_, embedded)| *embedded <= 2),
+            "{counts:?}"
+        );
+
+        // The second scan only counts the file it indexes, not the ones indexed before it.
+        statuses.lock().clear();
+        fs.insert_file(path!("/fake_project/c.rs"), b"fn c() {}".to_vec())
+            .await;
+        cx.run_until_parked();
+        assert_eq!(
+            cx.update(|cx| project_index.read(cx).status()),
+            Status::Idle
+        );
+        let counts = scanning_counts(&statuses.lock());
+        assert!(!counts.is_empty());
+        assert!(
+            counts
+                .iter()
+                .all(|(total, embedded)| *total == 1 && *embedded <= 1),
+            "{counts:?}"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_embed_files(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let provider = Arc::new(TestEmbeddingProvider::new(3, 26, |text| {
+            anyhow::ensure!(
+                !text.contains('g'),
+                "cannot embed text containing a 'g' character"
+            );
+            Ok(Embedding::new(
+                ('a'..='z')
+                    .map(|char| text.chars().filter(|c| *c == char).count() as f32)
+                    .collect(),
+            ))
+        }));
+
+        let (indexing_progress_tx, _) = channel::unbounded();
+        let indexing_entries = Arc::new(IndexingEntrySet::new(indexing_progress_tx));
+
+        let (chunked_files_tx, chunked_files_rx) = channel::unbounded::<ChunkedFile>();
+        chunked_files_tx
+            .send_blocking(ChunkedFile {
+                path: Path::new("test1.md").into(),
+                mtime: None,
+                handle: indexing_entries.insert(ProjectEntryId::from_proto(0)),
+                text: "abcdefghijklmnop".to_string(),
+                chunks: [0..4, 4..8, 8..12, 12..16]
+                    .into_iter()
+                    .map(|range| Chunk {
+                        range,
+                        digest: Default::default(),
+                    })
+                    .collect(),
+                existing_embeddings: HashMap::default(),
+            })
+            .unwrap();
+        chunked_files_tx
+            .send_blocking(ChunkedFile {
+                path: Path::new("test2.md").into(),
+                mtime: None,
+                handle: indexing_entries.insert(ProjectEntryId::from_proto(1)),
+                text: "qrstuvwxyz".to_string(),
+                chunks: [0..4, 4..8, 8..10]
+                    .into_iter()
+                    .map(|range| Chunk {
+                        range,
+                        digest: Default::default(),
+                    })
+                    .collect(),
+                existing_embeddings: HashMap::default(),
+            })
+            .unwrap();
+        chunked_files_tx.close();
+
+        let embed_files_task = cx.update(|cx| {
+            EmbeddingIndex::embed_files(
+                provider.clone(),
+                chunked_files_rx,
+                &ChunkingOptions::default(),
+                DEFAULT_EMBED_TIMEOUT,
+                cx,
+            )
+        });
+        embed_files_task.task.await.unwrap();
+
+        let embedded_files_rx = embed_files_task.files;
+        let mut embedded_files = Vec::new();
+        while let Ok((embedded_file, _)) = embedded_files_rx.recv().await {
+            embedded_files.push(embedded_file);
+        }
+
+        assert_eq!(indexing_entries.queued_count(), 2);
+        assert_eq!(indexing_entries.embedded_chunk_count(), 3);
+        assert_eq!(embedded_files.len(), 1);
+        assert_eq!(embedded_files[0].path.as_ref(), Path::new("test2.md"));
+        assert_eq!(
+            embedded_files[0]
+                .chunks
+                .iter()
+                .map(|embedded_chunk| { embedded_chunk.embedding.clone() })
+                .collect::<Vec<Embedding>>(),
+            vec![
+                (provider.compute_embedding)("qrst").unwrap(),
+                (provider.compute_embedding)("uvwx").unwrap(),
+                (provider.compute_embedding)("yz").unwrap(),
+            ],
+        );
+    }
+
+    /// Never responds, like a provider whose server has hung.
+    pub struct HangingEmbeddingProvider;
+
+    impl EmbeddingProvider for HangingEmbeddingProvider {
+        fn embed<'a>(
+            &'a self,
+            _texts: &'a [TextToEmbed<'a>],
+        ) -> BoxFuture<'a, Result<Vec<Embedding>>> {
+            future::pending().boxed()
+        }
+
+        fn batch_size(&self) -> usize {
+            16
+        }
+
+        fn dimension(&self) -> usize {
+            2
+        }
+
+        fn model_id(&self) -> &str {
+            "hanging"
+        }
+    }
+
+    #[gpui::test]
+    async fn test_search_times_out(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        init_test(cx);
+
+        cx.update(|cx| {
+            // This functionality is staff-flagged.
+            cx.update_flags(true, vec![]);
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut semantic_index = SemanticDb::new(
+            temp_dir.path().into(),
+            Arc::new(HangingEmbeddingProvider),
+            &mut cx.to_async(),
+        )
+        .await
+        .unwrap()
+        .with_embed_timeout(Duration::from_secs(5));
+
+        let (_, project) = test_project(json!({ "main.rs": "fn main() {}\n" }), cx).await;
+        let project_index =
+            cx.update(|cx| semantic_index.create_project_index(project.clone(), cx));
+        cx.run_until_parked();
+
+        let search = cx.update(|cx| {
+            project_index
+                .read(cx)
+                .search(vec!["entry point".into()], 4, cx)
+        });
+        cx.run_until_parked();
+        cx.executor().advance_clock(Duration::from_secs(5));
+
+        // The query fails to embed, rather than leaving the search pending forever.
+        assert!(search.await.is_err());
+    }
+
+    #[gpui::test]
+    async fn test_load_search_results(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        let project_path = Path::new("/fake_project");
+
+        let file1_content = "one\ntwo\nthree\nfour\nfive\n";
+        let file2_content = "aaa\nbbb\nccc\nddd\neee\n";
+
+        fs.insert_tree(
+            project_path,
+            json!({
+                "file1.txt": file1_content,
+                "file2.txt": file2_content,
+            }),
+        )
+        .await;
+
+        let fs = fs as Arc<dyn Fs>;
+        let project = Project::test(fs.clone(), [project_path], cx).await;
+        let worktree = project.read_with(cx, |project, cx| project.worktrees(cx).next().unwrap());
+
+        // chunk that is already newline-aligned
+        let search_results = vec![SearchResult {
+            worktree: worktree.clone(),
+            path: Path::new("file1.txt").into(),
+            range: 0..file1_content.find("four").unwrap(),
+            score: 0.5,
+            query_index: 0,
+        }];
+        assert_eq!(
+            SemanticDb::load_results(search_results, &fs, &cx.to_async())
+                .await
+                .unwrap(),
+            &[LoadedSearchResult {
+                path: Path::new("file1.txt").into(),
+                full_path: "fake_project/file1.txt".into(),
+                excerpt_content: "one\ntwo\nthree\n".into(),
+                row_range: 0..=2,
+                query_index: 0,
+            }]
+        );
+
+        // chunk that is *not* newline-aligned
+        let search_results = vec![SearchResult {
             worktree: worktree.clone(),
             path: Path::new("file1.txt").into(),
             range: file1_content.find("two").unwrap() + 1..file1_content.find("four").unwrap() + 2,
@@ -629,5 +1968,56 @@ mod tests {
                 }
             ]
         );
+
+        // context lines around a chunk
+        let search_results = vec![SearchResult {
+            worktree: worktree.clone(),
+            path: Path::new("file1.txt").into(),
+            range: file1_content.find("three").unwrap()..file1_content.find("four").unwrap(),
+            score: 0.5,
+            query_index: 0,
+        }];
+        assert_eq!(
+            SemanticDb::load_results_with_context(search_results, 1, &fs, &cx.to_async())
+                .await
+                .unwrap(),
+            &[LoadedSearchResult {
+                path: Path::new("file1.txt").into(),
+                full_path: "fake_project/file1.txt".into(),
+                excerpt_content: "two\nthree\nfour\n".into(),
+                row_range: 1..=3,
+                query_index: 0,
+            }]
+        );
+
+        // chunks whose context overlaps are merged, and context stops at the file's bounds
+        let search_results = vec![
+            SearchResult {
+                worktree: worktree.clone(),
+                path: Path::new("file1.txt").into(),
+                range: 0..file1_content.find("two").unwrap(),
+                score: 0.5,
+                query_index: 0,
+            },
+            SearchResult {
+                worktree: worktree.clone(),
+                path: Path::new("file1.txt").into(),
+                range: file1_content.find("five").unwrap()..file1_content.len(),
+                score: 0.5,
+                query_index: 0,
+            },
+        ];
+        assert_eq!(
+            SemanticDb::load_results_with_context(search_results, 2, &fs, &cx.to_async())
+                .await
+                .unwrap(),
+            &[LoadedSearchResult {
+                path: Path::new("file1.txt").into(),
+                full_path: "fake_project/file1.txt".into(),
+                excerpt_content: file1_content.into(),
+                row_range: 0..=4,
+                query_index: 0,
+            }]
+        );
     }
 }
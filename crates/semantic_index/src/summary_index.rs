@@ -380,7 +380,7 @@ impl SummaryIndex {
                     | project::PathChange::Updated
                     | project::PathChange::AddedOrUpdated => {
                         if let Some(entry) = worktree.entry_for_id(*entry_id) {
-                            if entry.is_file() {
+                            if entry.is_file() && !entry.is_ignored {
                                 let needs_summary = Self::add_to_backlog(
                                     Arc::clone(&backlog),
                                     digest_db,
@@ -1,18 +1,42 @@
+use crate::chunking::ChunkingOptions;
 use crate::embedding::EmbeddingProvider;
-use crate::embedding_index::EmbeddingIndex;
+use crate::embedding_index::{EmbeddingIndex, reset_stale_embeddings};
 use crate::indexing::IndexingEntrySet;
 use crate::summary_index::SummaryIndex;
 use anyhow::Result;
 use fs::Fs;
 use futures::future::Shared;
 use gpui::{App, AppContext as _, AsyncApp, Context, Entity, Subscription, Task, WeakEntity};
+use heed::types::{Str, Unit};
 use language::LanguageRegistry;
 use log;
 use project::{UpdatedEntriesSet, Worktree};
 use smol::channel;
-use std::sync::Arc;
+use std::{path::Path, sync::Arc, time::Duration};
 use util::ResultExt;
 
+/// The database recording the absolute path of every indexed worktree, so that their databases
+/// can be found without inspecting the names of every database in the environment.
+pub(crate) const WORKTREE_REGISTRY_DB_NAME: &str = "indexed-worktrees";
+
+pub(crate) fn embedding_db_name(worktree_abs_path: &Path) -> String {
+    worktree_abs_path.to_string_lossy().into_owned()
+}
+
+// Prepend something that wouldn't be found at the beginning of an absolute path, so we don't get
+// db key namespace conflicts with embeddings, which use the abs path as a key.
+pub(crate) fn file_digest_db_name(worktree_abs_path: &Path) -> String {
+    format!("digests-{}", worktree_abs_path.to_string_lossy())
+}
+
+pub(crate) fn summary_db_name(worktree_abs_path: &Path) -> String {
+    format!("summaries-{}", worktree_abs_path.to_string_lossy())
+}
+
+pub(crate) fn embedding_metadata_db_name(worktree_abs_path: &Path) -> String {
+    format!("embedding-metadata-{}", worktree_abs_path.to_string_lossy())
+}
+
 #[derive(Clone)]
 pub enum WorktreeIndexHandle {
     Loading {
@@ -41,6 +65,8 @@ impl WorktreeIndex {
         fs: Arc<dyn Fs>,
         status_tx: channel::Sender<()>,
         embedding_provider: Arc<dyn EmbeddingProvider>,
+        chunking_options: ChunkingOptions,
+        embed_timeout: Duration,
         cx: &mut App,
     ) -> Task<Result<Entity<Self>>> {
         let worktree_for_index = worktree.clone();
@@ -56,9 +82,25 @@ impl WorktreeIndex {
                     let db_connection = db_connection.clone();
                     async move {
                         let mut txn = db_connection.write_txn()?;
+                        let registry_db = db_connection.create_database::<Str, Unit>(
+                            &mut txn,
+                            Some(WORKTREE_REGISTRY_DB_NAME),
+                        )?;
+                        registry_db.put(&mut txn, &worktree_abs_path.to_string_lossy(), &())?;
                         let embedding_index = {
-                            let db_name = worktree_abs_path.to_string_lossy();
+                            let db_name = embedding_db_name(&worktree_abs_path);
                             let db = db_connection.create_database(&mut txn, Some(&db_name))?;
+                            let metadata_db_name = embedding_metadata_db_name(&worktree_abs_path);
+                            let metadata_db =
+                                db_connection.create_database(&mut txn, Some(&metadata_db_name))?;
+                            reset_stale_embeddings(
+                                &mut txn,
+                                db,
+                                metadata_db,
+                                embedding_provider.model_id(),
+                                embedding_provider.dimension(),
+                                &chunking_options,
+                            )?;
 
                             EmbeddingIndex::new(
                                 worktree_for_index,
@@ -67,24 +109,18 @@ impl WorktreeIndex {
                                 db,
                                 language_registry,
                                 embedding_provider,
+                                chunking_options,
+                                embed_timeout,
                                 Arc::clone(&entries_being_indexed),
                             )
                         };
                         let summary_index = {
                             let file_digest_db = {
-                                let db_name =
-                                // Prepend something that wouldn't be found at the beginning of an
-                                // absolute path, so we don't get db key namespace conflicts with
-                                // embeddings, which use the abs path as a key.
-                                format!("digests-{}", worktree_abs_path.to_string_lossy());
+                                let db_name = file_digest_db_name(&worktree_abs_path);
                                 db_connection.create_database(&mut txn, Some(&db_name))?
                             };
                             let summary_db = {
-                                let db_name =
-                                // Prepend something that wouldn't be found at the beginning of an
-                                // absolute path, so we don't get db key namespace conflicts with
-                                // embeddings, which use the abs path as a key.
-                                format!("summaries-{}", worktree_abs_path.to_string_lossy());
+                                let db_name = summary_db_name(&worktree_abs_path);
                                 db_connection.create_database(&mut txn, Some(&db_name))?
                             };
                             SummaryIndex::new(